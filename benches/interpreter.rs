@@ -0,0 +1,39 @@
+// cargo bench
+use x86_simulator::asm::assemble;
+use x86_simulator::{Budget, X86Cpu};
+
+// the demo's factorial loop, repeated 40 * 65535 times
+fn load_benchmark_program(cpu: &mut X86Cpu) {
+    let program = assemble("
+        mov di, 40
+    outer:
+        mov si, 0xFFFF
+    inner:
+        mov ax, 1
+        mov cx, 8
+    factorial:
+        mul cx
+        dec cx
+        cmp cx, 1
+        jne factorial
+        dec si
+        jnz inner
+        dec di
+        jnz outer
+        hlt
+    ").expect("benchmark program assembles");
+
+    cpu.load(0, &program.bytes);
+}
+
+// `cargo bench` for lazy flags, `cargo bench --features eager-flags` for
+// FLAGS computed after every ALU instruction, to compare against
+fn main() {
+    let label = if cfg!(feature = "eager-flags") { "eager flags" } else { "lazy flags" };
+    let mut cpu = X86Cpu::new();
+    load_benchmark_program(&mut cpu);
+    let start = std::time::Instant::now();
+    let steps = cpu.run(Budget::Unlimited).retired;
+    let secs = start.elapsed().as_secs_f64();
+    println!("{}: {} instructions in {:.3}s ({:.1} M instructions/s)", label, steps, secs, steps as f64 / secs / 1e6);
+}
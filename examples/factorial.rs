@@ -0,0 +1,40 @@
+use x86_simulator::asm::assemble;
+use x86_simulator::{Budget, X86Cpu};
+
+fn load_factorial_program(cpu: &mut X86Cpu) {
+    let program = assemble("
+        mov ax, 1
+        mov cx, 5
+    next:
+        mul cx
+        dec cx
+        cmp cx, 1
+        jne next
+        push ax
+        hlt
+    ").expect("factorial program assembles");
+
+    cpu.load(0, &program.bytes);
+}
+
+
+fn main() {
+    println!("--- x86 Real Mode Simulator ---");
+    let mut cpu = X86Cpu::new();
+    load_factorial_program(&mut cpu);
+    println!("Calculating 5! (Factorial of 5)...");
+    let mut steps = 0;
+
+    let outcome = cpu.run_until(Budget::Instructions(50), |cpu| {
+        steps += 1;
+        println!("Step {:02} | IP: 0x{:04X} | AX: {:5} | CX: {:5}",
+                 steps, cpu.regs.ip, cpu.regs.ax, cpu.regs.cx);
+        false
+    });
+
+    println!("\nSimulation stopped: {} after {} instructions ({} clocks).",
+             outcome.stop, outcome.retired, outcome.cycles);
+    let result = cpu.pop();
+    println!("Final Factorial Result on Stack: {}", result);
+
+}
@@ -0,0 +1,1046 @@
+// A two-pass assembler for NASM-style 8086 source, so test programs and
+// demos can be written as text instead of hand-encoded bytes
+//
+// The first pass sizes every statement and collects the labels; the second
+// emits the bytes. Anything that refers to a symbol defined further down
+// gets its long form in both passes (a near JMP, a 16-bit displacement or
+// immediate), so the addresses the first pass worked out still hold.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decode::Width;
+use crate::disasm::{REG16, REG8, SEG};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembled code: `bytes` belong at offset `origin`, as set by `org`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+/// Assemble NASM-style source: one instruction or directive per line with
+/// optional `label:` and `; comment`, `.local` labels scoped to the last
+/// plain label, `db`/`dw` data, `equ`, `org` and `times`. Numbers are
+/// decimal, `0x1F`/`1Fh` hex or `0b101`/`101b` binary; expressions may use
+/// `$`, `$$`, quoted characters and the usual C operators.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut asm = Assembler::default();
+    for pass in 1..=2 {
+        asm.pass = pass;
+        asm.origin = 0;
+        asm.out.clear();
+        asm.scope.clear();
+        for (i, text) in source.lines().enumerate() {
+            asm.line = i + 1;
+            asm.start = asm.here();
+            asm.line(text).map_err(|message| AsmError { line: i + 1, message })?;
+            if asm.here() > 0x10000 {
+                return Err(AsmError { line: i + 1, message: "program does not fit in a 64 KiB segment".into() });
+            }
+        }
+    }
+    let labels = asm.symbols.into_iter().filter(|(_, s)| s.label).map(|(name, s)| (name, s.value as u16)).collect();
+    Ok(Assembly { origin: asm.origin, bytes: asm.out, labels })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Num(i64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Tok>, String> {
+    let b = text.as_bytes();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        let word_end = |from: usize| {
+            from + b[from..].iter().take_while(|&&c| c.is_ascii_alphanumeric() || b"_.?@$#".contains(&c)).count()
+        };
+        match c {
+            b';' => break,
+            _ if c.is_ascii_whitespace() => i += 1,
+            b'\'' | b'"' | b'`' => {
+                let len = b[i + 1..].iter().position(|&q| q == c).ok_or("unterminated string")?;
+                toks.push(Tok::Str(b[i + 1..i + 1 + len].to_vec()));
+                i += len + 2;
+            }
+            b'0'..=b'9' => {
+                let end = word_end(i);
+                toks.push(Tok::Num(number(&text[i..end]).ok_or_else(|| format!("bad number {}", &text[i..end]))?));
+                i = end;
+            }
+            b'$' if b.get(i + 1) == Some(&b'$') => {
+                toks.push(Tok::Ident("$$".into()));
+                i += 2;
+            }
+            b'$' => {
+                toks.push(Tok::Ident("$".into()));
+                i += 1;
+            }
+            _ if c.is_ascii_alphabetic() || b"_.?@".contains(&c) => {
+                let end = word_end(i);
+                toks.push(Tok::Ident(text[i..end].to_string()));
+                i = end;
+            }
+            _ => {
+                let two = text.get(i..i + 2);
+                let punct = match (two, c) {
+                    (Some("<<"), _) => "<<",
+                    (Some(">>"), _) => ">>",
+                    (_, b'+') => "+", (_, b'-') => "-", (_, b'*') => "*", (_, b'/') => "/",
+                    (_, b'%') => "%", (_, b'(') => "(", (_, b')') => ")", (_, b'[') => "[",
+                    (_, b']') => "]", (_, b',') => ",", (_, b':') => ":", (_, b'~') => "~",
+                    (_, b'&') => "&", (_, b'|') => "|", (_, b'^') => "^",
+                    _ => return Err(format!("unexpected character {:?}", c as char)),
+                };
+                toks.push(Tok::Punct(punct));
+                i += punct.len();
+            }
+        }
+    }
+    Ok(toks)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let t = text.to_ascii_lowercase().replace('_', "");
+    let (digits, radix) = if let Some(hex) = t.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = t.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(bin) = t.strip_prefix("0b") {
+        (bin, 2)
+    } else if let Some(bin) = t.strip_suffix('b') {
+        (bin, 2)
+    } else {
+        (&t[..], 10)
+    };
+    match digits.bytes().next() {
+        Some(c) if c.is_ascii_alphanumeric() => i64::from_str_radix(digits, radix).ok(),
+        _ => None,
+    }
+}
+
+// an expression's value, and whether it depends on a symbol defined further down
+#[derive(Clone, Copy, Debug)]
+struct Value {
+    n: i64,
+    forward: bool,
+}
+
+impl Value {
+    // fits in a sign-extended byte, and will in the second pass too
+    fn short(self) -> bool {
+        !self.forward && (-0x80..=0x7F).contains(&(self.n as u16 as i16))
+    }
+}
+
+struct Symbol {
+    value: i64,
+    line: usize,
+    late: bool, // an `equ` whose value depends on a later symbol
+    label: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hint {
+    Byte,
+    Word,
+    Far, // `far` or `dword`: a far jump or call through memory
+    Short,
+    Near,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Mem {
+    seg: Option<u8>,
+    rm: Option<u8>, // None for a direct [disp16]
+    disp: Value,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Arg {
+    Reg(Width, u8),
+    Seg(u8),
+    Mem(Mem),
+    Imm(Value),
+    Far(Value, Value), // segment, offset
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Operand {
+    arg: Arg,
+    hint: Option<Hint>,
+}
+
+fn register(name: &str) -> Option<Arg> {
+    let name = name.to_ascii_lowercase();
+    let find = |names: &[&str]| names.iter().position(|&r| r == name).map(|i| i as u8);
+    find(&REG8).map(|r| Arg::Reg(Width::Byte, r))
+        .or_else(|| find(&REG16).map(|r| Arg::Reg(Width::Word, r)))
+        .or_else(|| find(&SEG).map(Arg::Seg))
+}
+
+fn segment(name: &str) -> Option<u8> {
+    match register(name) {
+        Some(Arg::Seg(s)) => Some(s),
+        _ => None,
+    }
+}
+
+fn is_rm(arg: &Arg) -> bool {
+    matches!(arg, Arg::Reg(..) | Arg::Mem(_))
+}
+
+// split at commas outside parentheses and brackets
+fn split_commas(t: &[Tok]) -> Vec<&[Tok]> {
+    let mut parts = Vec::new();
+    let (mut depth, mut from) = (0, 0);
+    for (i, tok) in t.iter().enumerate() {
+        match tok {
+            Tok::Punct("(" | "[") => depth += 1,
+            Tok::Punct(")" | "]") => depth -= 1,
+            Tok::Punct(",") if depth == 0 => {
+                parts.push(&t[from..i]);
+                from = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !t.is_empty() {
+        parts.push(&t[from..]);
+    }
+    parts
+}
+
+fn implied(name: &str) -> Option<u8> {
+    Some(match name {
+        "cbw" => 0x98, "cwd" => 0x99, "lahf" => 0x9F, "sahf" => 0x9E,
+        "pushf" => 0x9C, "popf" => 0x9D, "xlat" | "xlatb" => 0xD7,
+        "daa" => 0x27, "das" => 0x2F, "aaa" => 0x37, "aas" => 0x3F,
+        "clc" => 0xF8, "stc" => 0xF9, "cmc" => 0xF5, "cld" => 0xFC,
+        "std" => 0xFD, "cli" => 0xFA, "sti" => 0xFB,
+        "int3" => 0xCC, "into" => 0xCE, "iret" => 0xCF,
+        "nop" => 0x90, "hlt" => 0xF4, "wait" | "fwait" => 0x9B,
+        "movsb" => 0xA4, "movsw" => 0xA5, "cmpsb" => 0xA6, "cmpsw" => 0xA7,
+        "stosb" => 0xAA, "stosw" => 0xAB, "lodsb" => 0xAC, "lodsw" => 0xAD,
+        "scasb" => 0xAE, "scasw" => 0xAF,
+        _ => return None,
+    })
+}
+
+fn condition(name: &str) -> Option<u8> {
+    Some(match name {
+        "jo" => 0, "jno" => 1, "jb" | "jc" | "jnae" => 2, "jae" | "jnb" | "jnc" => 3,
+        "je" | "jz" => 4, "jne" | "jnz" => 5, "jbe" | "jna" => 6, "ja" | "jnbe" => 7,
+        "js" => 8, "jns" => 9, "jp" | "jpe" => 10, "jnp" | "jpo" => 11,
+        "jl" | "jnge" => 12, "jge" | "jnl" => 13, "jle" | "jng" => 14, "jg" | "jnle" => 15,
+        _ => return None,
+    })
+}
+
+#[derive(Default)]
+struct Assembler {
+    pass: u8,
+    line: usize,
+    origin: u16,
+    out: Vec<u8>,
+    start: i64, // `$`, the address of the current statement
+    symbols: HashMap<String, Symbol>,
+    scope: String, // the last non-local label
+}
+
+impl Assembler {
+    fn here(&self) -> i64 {
+        self.origin as i64 + self.out.len() as i64
+    }
+
+    fn line(&mut self, text: &str) -> Result<(), String> {
+        let toks = tokenize(text)?;
+        let mut t = &toks[..];
+        // `name:`, or a bare name in front of a data directive
+        let mut label = None;
+        match t {
+            [Tok::Ident(name), Tok::Punct(":"), rest @ ..] => {
+                label = Some(name.clone());
+                t = rest;
+            }
+            [Tok::Ident(name), Tok::Ident(next), ..] if matches!(next.to_ascii_lowercase().as_str(), "db" | "dw" | "equ" | "times") => {
+                label = Some(name.clone());
+                t = &t[1..];
+            }
+            _ => {}
+        }
+        match (label, t) {
+            (Some(name), [Tok::Ident(equ), rest @ ..]) if equ.eq_ignore_ascii_case("equ") => {
+                let v = self.eval(rest)?;
+                self.define(&name, v.n, v.forward, false)
+            }
+            (Some(name), _) => {
+                let here = self.here();
+                self.define(&name, here, false, true)?;
+                self.statement(t)
+            }
+            (None, _) => self.statement(t),
+        }
+    }
+
+    fn define(&mut self, name: &str, value: i64, late: bool, label: bool) -> Result<(), String> {
+        if register(name).is_some() || name.starts_with('$') {
+            return Err(format!("{} cannot be used as a label", name));
+        }
+        let name = self.qualify(name);
+        if label && !name.contains('.') {
+            self.scope = name.clone();
+        }
+        match self.symbols.get(&name) {
+            Some(_) if self.pass == 1 => return Err(format!("{} is already defined", name)),
+            Some(old) if label && old.value != value => return Err(format!("{} moved between passes", name)),
+            _ => {}
+        }
+        self.symbols.insert(name, Symbol { value, line: self.line, late, label });
+        Ok(())
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') { format!("{}{}", self.scope, name) } else { name.to_string() }
+    }
+
+    fn statement(&mut self, t: &[Tok]) -> Result<(), String> {
+        let (word, rest) = match t {
+            [] => return Ok(()),
+            [Tok::Ident(word), rest @ ..] => (word.to_ascii_lowercase(), rest),
+            _ => return Err("expected an instruction or directive".into()),
+        };
+        match word.as_str() {
+            "org" => {
+                let v = self.eval(rest)?;
+                if v.forward || !(0..=0xFFFF).contains(&v.n) {
+                    return Err("org needs an address from 0 to FFFFh defined above it".into());
+                }
+                if !self.out.is_empty() {
+                    return Err("org must come before any code or data".into());
+                }
+                self.origin = v.n as u16;
+                self.start = self.here();
+                Ok(())
+            }
+            "bits" => match self.eval(rest)?.n {
+                16 => Ok(()),
+                _ => Err("only 16-bit code is supported".into()),
+            },
+            "times" => {
+                let mut pos = 0;
+                let count = self.expr(rest, &mut pos, 0)?;
+                if count.forward || !(0..=0x10000).contains(&count.n) {
+                    return Err("times needs a count from 0 to 65536 defined above it".into());
+                }
+                for _ in 0..count.n {
+                    self.start = self.here();
+                    self.statement(&rest[pos..])?;
+                    if self.here() > 0x10000 {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            "db" | "dw" => self.data(if word == "db" { Width::Byte } else { Width::Word }, rest),
+            "equ" => Err("equ needs a name".into()),
+            "rep" | "repe" | "repz" => self.prefix(0xF3, rest),
+            "repne" | "repnz" => self.prefix(0xF2, rest),
+            "lock" => self.prefix(0xF0, rest),
+            _ => match segment(&word) {
+                Some(s) if rest.first() != Some(&Tok::Punct(":")) => self.prefix(0x26 | s << 3, rest),
+                _ => {
+                    let ops = split_commas(rest).into_iter().map(|o| self.operand(o)).collect::<Result<Vec<_>, _>>()?;
+                    self.instruction(&word, &ops)
+                }
+            },
+        }
+    }
+
+    fn prefix(&mut self, byte: u8, rest: &[Tok]) -> Result<(), String> {
+        self.out.push(byte);
+        self.statement(rest)
+    }
+
+    fn data(&mut self, w: Width, t: &[Tok]) -> Result<(), String> {
+        for item in split_commas(t) {
+            match item {
+                [Tok::Str(s)] => {
+                    self.out.extend_from_slice(s);
+                    if w == Width::Word && s.len() % 2 == 1 {
+                        self.out.push(0);
+                    }
+                }
+                _ => {
+                    let v = self.eval(item)?;
+                    self.imm(v, w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, t: &[Tok]) -> Result<Value, String> {
+        let mut pos = 0;
+        let v = self.expr(t, &mut pos, 0)?;
+        match t.get(pos) {
+            None => Ok(v),
+            Some(tok) => Err(format!("unexpected {} in expression", describe(tok))),
+        }
+    }
+
+    // precedence climbing over | ^ & << >> + - * / %
+    fn expr(&self, t: &[Tok], pos: &mut usize, min: u8) -> Result<Value, String> {
+        let mut lhs = self.unary(t, pos)?;
+        while let Some(Tok::Punct(op)) = t.get(*pos) {
+            let prec = match *op {
+                "|" => 1,
+                "^" => 2,
+                "&" => 3,
+                "<<" | ">>" => 4,
+                "+" | "-" => 5,
+                "*" | "/" | "%" => 6,
+                _ => break,
+            };
+            if prec < min {
+                break;
+            }
+            *pos += 1;
+            let rhs = self.expr(t, pos, prec + 1)?;
+            let forward = lhs.forward || rhs.forward;
+            let (a, b) = (lhs.n, rhs.n);
+            let n = match *op {
+                "|" => a | b,
+                "^" => a ^ b,
+                "&" => a & b,
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                // a first-pass placeholder may be zero
+                _ if b == 0 && forward => 0,
+                _ if b == 0 => return Err("division by zero".into()),
+                "/" => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            };
+            lhs = Value { n, forward };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&self, t: &[Tok], pos: &mut usize) -> Result<Value, String> {
+        let tok = t.get(*pos).ok_or("expression expected")?;
+        *pos += 1;
+        match tok {
+            Tok::Punct("-") => self.unary(t, pos).map(|v| Value { n: v.n.wrapping_neg(), ..v }),
+            Tok::Punct("+") => self.unary(t, pos),
+            Tok::Punct("~") => self.unary(t, pos).map(|v| Value { n: !v.n, ..v }),
+            Tok::Punct("(") => {
+                let v = self.expr(t, pos, 0)?;
+                if t.get(*pos) != Some(&Tok::Punct(")")) {
+                    return Err("missing )".into());
+                }
+                *pos += 1;
+                Ok(v)
+            }
+            Tok::Num(n) => Ok(Value { n: *n, forward: false }),
+            // a quoted constant is its bytes, little-endian
+            Tok::Str(s) if !s.is_empty() && s.len() <= 8 => {
+                Ok(Value { n: s.iter().rev().fold(0, |acc, &c| acc << 8 | c as i64), forward: false })
+            }
+            Tok::Ident(name) if name == "$" => Ok(Value { n: self.start, forward: false }),
+            Tok::Ident(name) if name == "$$" => Ok(Value { n: self.origin as i64, forward: false }),
+            Tok::Ident(name) if register(name).is_none() => match self.symbols.get(&self.qualify(name)) {
+                Some(s) => Ok(Value { n: s.value, forward: s.late || s.line > self.line }),
+                None if self.pass == 1 => Ok(Value { n: 0, forward: true }),
+                None => Err(format!("undefined symbol {}", name)),
+            },
+            _ => Err(format!("unexpected {} in expression", describe(tok))),
+        }
+    }
+
+    fn operand(&self, mut t: &[Tok]) -> Result<Operand, String> {
+        let mut hint = None;
+        while let [Tok::Ident(word), rest @ ..] = t {
+            let h = match word.to_ascii_lowercase().as_str() {
+                "byte" => Hint::Byte,
+                "word" => Hint::Word,
+                "dword" | "far" => Hint::Far,
+                "short" => Hint::Short,
+                "near" => Hint::Near,
+                "ptr" => {
+                    t = rest;
+                    continue;
+                }
+                _ => break,
+            };
+            if hint.is_some() {
+                return Err("more than one size or distance given".into());
+            }
+            hint = Some(h);
+            t = rest;
+        }
+        let arg = match t {
+            [] => return Err("operand expected".into()),
+            [Tok::Ident(name)] if register(name).is_some() => register(name).unwrap(),
+            [Tok::Punct("["), inner @ .., Tok::Punct("]")] => Arg::Mem(self.mem(None, inner)?),
+            [Tok::Ident(s), Tok::Punct(":"), Tok::Punct("["), inner @ .., Tok::Punct("]")] if segment(s).is_some() => {
+                Arg::Mem(self.mem(segment(s), inner)?)
+            }
+            _ => {
+                let mut pos = 0;
+                let v = self.expr(t, &mut pos, 0)?;
+                if t.get(pos) == Some(&Tok::Punct(":")) {
+                    Arg::Far(v, self.eval(&t[pos + 1..])?)
+                } else {
+                    self.eval(t).map(Arg::Imm)?
+                }
+            }
+        };
+        Ok(Operand { arg, hint })
+    }
+
+    // the inside of [...]: an optional segment, then base and index registers
+    // added to a displacement
+    fn mem(&self, mut seg: Option<u8>, mut t: &[Tok]) -> Result<Mem, String> {
+        if let [Tok::Ident(s), Tok::Punct(":"), rest @ ..] = t {
+            if let Some(n) = segment(s) {
+                if seg.is_some() {
+                    return Err("two segment overrides".into());
+                }
+                (seg, t) = (Some(n), rest);
+            }
+        }
+        let mut regs = Vec::new();
+        let mut disp = Vec::new();
+        for (i, tok) in t.iter().enumerate() {
+            let name = match tok {
+                Tok::Ident(name) => name.to_ascii_lowercase(),
+                _ => {
+                    disp.push(tok.clone());
+                    continue;
+                }
+            };
+            match name.as_str() {
+                "bx" | "bp" | "si" | "di" => {
+                    let before = if i == 0 { None } else { t.get(i - 1) };
+                    let added = matches!(before, None | Some(Tok::Punct("+")))
+                        && matches!(t.get(i + 1), None | Some(Tok::Punct("+" | "-")));
+                    if !added {
+                        return Err(format!("{} can only be added to an address", name));
+                    }
+                    regs.push(name);
+                    disp.push(Tok::Num(0));
+                }
+                _ if register(&name).is_some() => return Err(format!("{} cannot address memory", name)),
+                _ => disp.push(tok.clone()),
+            }
+        }
+        let disp = self.eval(&disp)?;
+        regs.sort();
+        let regs: Vec<&str> = regs.iter().map(String::as_str).collect();
+        let rm = match regs[..] {
+            [] => None,
+            ["bx", "si"] => Some(0),
+            ["bx", "di"] => Some(1),
+            ["bp", "si"] => Some(2),
+            ["bp", "di"] => Some(3),
+            ["si"] => Some(4),
+            ["di"] => Some(5),
+            ["bp"] => Some(6),
+            ["bx"] => Some(7),
+            _ => return Err(format!("[{}] is not an 8086 addressing mode", regs.join("+"))),
+        };
+        Ok(Mem { seg, rm, disp })
+    }
+
+    // the value as a byte or word, range-checked once every symbol is known
+    fn fits(&self, v: Value, w: Width) -> Result<u16, String> {
+        let (range, what) = match w {
+            Width::Byte => (-0x80..=0xFF, "byte"),
+            Width::Word => (-0x8000..=0xFFFF, "word"),
+        };
+        if self.pass == 2 && !range.contains(&v.n) {
+            return Err(format!("{} does not fit in a {}", v.n, what));
+        }
+        Ok(v.n as u16)
+    }
+
+    fn imm(&mut self, v: Value, w: Width) -> Result<(), String> {
+        let n = self.fits(v, w)?;
+        match w {
+            Width::Byte => self.out.push(n as u8),
+            Width::Word => self.out.extend_from_slice(&n.to_le_bytes()),
+        }
+        Ok(())
+    }
+
+    fn modrm(&mut self, reg: u8, rm: &Arg) -> Result<(), String> {
+        match rm {
+            Arg::Reg(_, r) => self.out.push(0xC0 | reg << 3 | r),
+            Arg::Mem(Mem { rm: None, disp, .. }) => {
+                self.out.push(0x06 | reg << 3);
+                self.imm(*disp, Width::Word)?;
+            }
+            // [bp] has no mod 00 form; it is [bp+0]
+            Arg::Mem(Mem { rm: Some(r), disp, .. }) if !disp.forward && disp.n == 0 && *r != 6 => self.out.push(reg << 3 | r),
+            Arg::Mem(Mem { rm: Some(r), disp, .. }) if disp.short() => {
+                self.fits(*disp, Width::Word)?;
+                self.out.extend_from_slice(&[0x40 | reg << 3 | r, disp.n as u8]);
+            }
+            Arg::Mem(Mem { rm: Some(r), disp, .. }) => {
+                self.out.push(0x80 | reg << 3 | r);
+                self.imm(*disp, Width::Word)?;
+            }
+            _ => return Err("expected a register or memory operand".into()),
+        }
+        Ok(())
+    }
+
+    // displacements are relative to the end of the instruction, which for
+    // these is the end of the displacement itself
+    fn rel8(&mut self, target: Value) -> Result<(), String> {
+        let d = target.n.wrapping_sub(self.here() + 1) as u16 as i16;
+        if self.pass == 2 && !(-0x80..=0x7F).contains(&d) {
+            return Err(format!("jump target is {} bytes away, out of short range", d));
+        }
+        self.out.push(d as u8);
+        Ok(())
+    }
+
+    fn rel16(&mut self, target: Value) -> Result<(), String> {
+        self.fits(target, Width::Word)?;
+        let d = target.n.wrapping_sub(self.here() + 2) as u16;
+        self.out.extend_from_slice(&d.to_le_bytes());
+        Ok(())
+    }
+
+    fn instruction(&mut self, name: &str, ops: &[Operand]) -> Result<(), String> {
+        let bad = || Err(format!("invalid operands for {}", name));
+        if let Some(seg) = ops.iter().find_map(|o| match o.arg { Arg::Mem(m) => m.seg, _ => None }) {
+            self.out.push(0x26 | seg << 3);
+        }
+        let args: Vec<Arg> = ops.iter().map(|o| o.arg).collect();
+        let alu = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"].iter().position(|&n| n == name);
+        let shift = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"].iter().position(|&n| n == name);
+        let unary = ["not", "neg", "mul", "imul", "div", "idiv"].iter().position(|&n| n == name);
+
+        if let Some(opcode) = implied(name) {
+            if !args.is_empty() {
+                return bad();
+            }
+            self.out.push(opcode);
+        } else if let Some(n) = alu {
+            let w = width(ops)?;
+            let (n, wb) = (n as u8, w as u8);
+            match args[..] {
+                [rm, Arg::Reg(_, r)] if is_rm(&rm) => {
+                    self.out.push(n << 3 | wb);
+                    self.modrm(r, &rm)?;
+                }
+                [Arg::Reg(_, r), rm @ Arg::Mem(_)] => {
+                    self.out.push(n << 3 | 2 | wb);
+                    self.modrm(r, &rm)?;
+                }
+                [rm, Arg::Imm(v)] if is_rm(&rm) => {
+                    if w == Width::Word && v.short() {
+                        self.fits(v, w)?;
+                        self.out.push(0x83);
+                        self.modrm(n, &rm)?;
+                        self.out.push(v.n as u8);
+                    } else if let Arg::Reg(_, 0) = rm {
+                        self.out.push(n << 3 | 4 | wb);
+                        self.imm(v, w)?;
+                    } else {
+                        self.out.push(0x80 | wb);
+                        self.modrm(n, &rm)?;
+                        self.imm(v, w)?;
+                    }
+                }
+                _ => return bad(),
+            }
+        } else if let Some(n) = shift {
+            // SAL is SHL; the decoder's /6 slot is not an instruction
+            let n = if n == 6 { 4 } else { n as u8 };
+            let wb = width(&ops[..1.min(ops.len())])? as u8;
+            match args[..] {
+                [rm, Arg::Imm(v)] if is_rm(&rm) && !v.forward && v.n == 1 => {
+                    self.out.push(0xD0 | wb);
+                    self.modrm(n, &rm)?;
+                }
+                [rm, Arg::Reg(Width::Byte, 1)] if is_rm(&rm) => {
+                    self.out.push(0xD2 | wb);
+                    self.modrm(n, &rm)?;
+                }
+                [_, _] => return Err("the 8086 shifts by 1 or by CL".into()),
+                _ => return bad(),
+            }
+        } else if let Some(n) = unary {
+            match args[..] {
+                [rm] if is_rm(&rm) => {
+                    self.out.push(0xF6 | width(ops)? as u8);
+                    self.modrm(n as u8 + 2, &rm)?;
+                }
+                [_, _] | [_, _, _] if name == "imul" => return Err("imul with more than one operand needs an 80186".into()),
+                _ => return bad(),
+            }
+        } else if let Some(cc) = condition(name) {
+            match ops {
+                [Operand { arg: Arg::Imm(v), hint: None | Some(Hint::Short) }] => {
+                    self.out.push(0x70 | cc);
+                    self.rel8(*v)?;
+                }
+                _ => return Err(format!("{} takes a short jump target", name)),
+            }
+        } else {
+            match (name, &args[..]) {
+                ("mov", _) => self.mov(ops)?,
+                ("test", _) => {
+                    let w = width(ops)?;
+                    let wb = w as u8;
+                    match args[..] {
+                        [rm, Arg::Reg(_, r)] | [Arg::Reg(_, r), rm @ Arg::Mem(_)] if is_rm(&rm) => {
+                            self.out.push(0x84 | wb);
+                            self.modrm(r, &rm)?;
+                        }
+                        [Arg::Reg(_, 0), Arg::Imm(v)] => {
+                            self.out.push(0xA8 | wb);
+                            self.imm(v, w)?;
+                        }
+                        [rm, Arg::Imm(v)] if is_rm(&rm) => {
+                            self.out.push(0xF6 | wb);
+                            self.modrm(0, &rm)?;
+                            self.imm(v, w)?;
+                        }
+                        _ => return bad(),
+                    }
+                }
+                ("xchg", _) => {
+                    let wb = width(ops)? as u8;
+                    match args[..] {
+                        [Arg::Reg(Width::Word, 0), Arg::Reg(Width::Word, r)] | [Arg::Reg(Width::Word, r), Arg::Reg(Width::Word, 0)] => {
+                            self.out.push(0x90 | r);
+                        }
+                        [rm, Arg::Reg(_, r)] | [Arg::Reg(_, r), rm @ Arg::Mem(_)] if is_rm(&rm) => {
+                            self.out.push(0x86 | wb);
+                            self.modrm(r, &rm)?;
+                        }
+                        _ => return bad(),
+                    }
+                }
+                ("inc" | "dec", [rm]) if is_rm(rm) => {
+                    let n = (name == "dec") as u8;
+                    match (rm, width(ops)?) {
+                        (Arg::Reg(_, r), Width::Word) => self.out.push(0x40 | n << 3 | r),
+                        (_, w) => {
+                            self.out.push(0xFE | w as u8);
+                            self.modrm(n, rm)?;
+                        }
+                    }
+                }
+                ("push", [Arg::Reg(Width::Word, r)]) => self.out.push(0x50 | r),
+                ("push", [Arg::Seg(s)]) => self.out.push(0x06 | s << 3),
+                ("push", [Arg::Imm(_)]) => return Err("push of an immediate needs an 80186".into()),
+                ("pop", [Arg::Reg(Width::Word, r)]) => self.out.push(0x58 | r),
+                ("pop", [Arg::Seg(1)]) => return Err("cannot pop cs".into()),
+                ("pop", [Arg::Seg(s)]) => self.out.push(0x07 | s << 3),
+                ("push" | "pop", [rm @ Arg::Mem(_)]) if matches!(ops[0].hint, None | Some(Hint::Word)) => {
+                    let (opcode, n) = if name == "push" { (0xFF, 6) } else { (0x8F, 0) };
+                    self.out.push(opcode);
+                    self.modrm(n, rm)?;
+                }
+                ("lea" | "lds" | "les", [Arg::Reg(Width::Word, r), m @ Arg::Mem(_)]) => {
+                    self.out.push(match name { "lea" => 0x8D, "lds" => 0xC5, _ => 0xC4 });
+                    self.modrm(*r, m)?;
+                }
+                ("in", [Arg::Reg(w, 0), Arg::Imm(port)]) => {
+                    self.out.push(0xE4 | *w as u8);
+                    self.imm(*port, Width::Byte)?;
+                }
+                ("in", [Arg::Reg(w, 0), Arg::Reg(Width::Word, 2)]) => self.out.push(0xEC | *w as u8),
+                ("out", [Arg::Imm(port), Arg::Reg(w, 0)]) => {
+                    self.out.push(0xE6 | *w as u8);
+                    self.imm(*port, Width::Byte)?;
+                }
+                ("out", [Arg::Reg(Width::Word, 2), Arg::Reg(w, 0)]) => self.out.push(0xEE | *w as u8),
+                ("int", [Arg::Imm(v)]) => {
+                    self.out.push(0xCD);
+                    self.imm(*v, Width::Byte)?;
+                }
+                ("aam" | "aad", []) => self.out.extend_from_slice(&[if name == "aam" { 0xD4 } else { 0xD5 }, 10]),
+                ("aam" | "aad", [Arg::Imm(v)]) => {
+                    self.out.push(if name == "aam" { 0xD4 } else { 0xD5 });
+                    self.imm(*v, Width::Byte)?;
+                }
+                ("ret", []) => self.out.push(0xC3),
+                ("retf", []) => self.out.push(0xCB),
+                ("ret" | "retf", [Arg::Imm(v)]) => {
+                    self.out.push(if name == "ret" { 0xC2 } else { 0xCA });
+                    self.imm(*v, Width::Word)?;
+                }
+                ("loop" | "loope" | "loopz" | "loopne" | "loopnz" | "jcxz", [Arg::Imm(v)]) => {
+                    self.out.push(match name { "loop" => 0xE2, "loope" | "loopz" => 0xE1, "jcxz" => 0xE3, _ => 0xE0 });
+                    self.rel8(*v)?;
+                }
+                ("jmp" | "call", _) => self.branch(name, ops)?,
+                ("esc", [Arg::Imm(v), rm]) if is_rm(rm) && !v.forward && (0..64).contains(&v.n) => {
+                    self.out.push(0xD8 | (v.n >> 3) as u8);
+                    self.modrm(v.n as u8 & 7, rm)?;
+                }
+                ("movs" | "cmps" | "stos" | "lods" | "scas", _) => {
+                    return Err(format!("write {}b or {}w", name, name));
+                }
+                _ if MNEMONICS.contains(&name) => return bad(),
+                _ => return Err(format!("unknown instruction {}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    fn mov(&mut self, ops: &[Operand]) -> Result<(), String> {
+        let w = width(ops)?;
+        let wb = w as u8;
+        let [dst, src] = ops else {
+            return Err("mov takes two operands".into());
+        };
+        match [dst.arg, src.arg] {
+            // the accumulator has short forms for a direct address
+            [Arg::Reg(_, 0), Arg::Mem(m @ Mem { rm: None, .. })] => {
+                self.out.push(0xA0 | wb);
+                self.imm(m.disp, Width::Word)?;
+            }
+            [Arg::Mem(m @ Mem { rm: None, .. }), Arg::Reg(_, 0)] => {
+                self.out.push(0xA2 | wb);
+                self.imm(m.disp, Width::Word)?;
+            }
+            [rm, Arg::Reg(_, r)] if is_rm(&rm) => {
+                self.out.push(0x88 | wb);
+                self.modrm(r, &rm)?;
+            }
+            [Arg::Reg(_, r), rm @ Arg::Mem(_)] => {
+                self.out.push(0x8A | wb);
+                self.modrm(r, &rm)?;
+            }
+            [rm, Arg::Seg(s)] if is_rm(&rm) => {
+                self.out.push(0x8C);
+                self.modrm(s, &rm)?;
+            }
+            [Arg::Seg(1), _] => return Err("cannot move into cs".into()),
+            [Arg::Seg(s), rm] if is_rm(&rm) => {
+                self.out.push(0x8E);
+                self.modrm(s, &rm)?;
+            }
+            [Arg::Reg(_, r), Arg::Imm(v)] => {
+                self.out.push(0xB0 | wb << 3 | r);
+                self.imm(v, w)?;
+            }
+            [rm @ Arg::Mem(_), Arg::Imm(v)] => {
+                self.out.push(0xC6 | wb);
+                self.modrm(0, &rm)?;
+                self.imm(v, w)?;
+            }
+            _ => return Err("invalid operands for mov".into()),
+        }
+        Ok(())
+    }
+
+    fn branch(&mut self, name: &str, ops: &[Operand]) -> Result<(), String> {
+        let call = name == "call";
+        let [Operand { arg, hint }] = *ops else {
+            return Err(format!("{} takes one operand", name));
+        };
+        match (arg, hint) {
+            (Arg::Imm(v), Some(Hint::Short)) if !call => {
+                self.out.push(0xEB);
+                self.rel8(v)?;
+            }
+            // an unqualified JMP is short when the target is known to be close
+            (Arg::Imm(v), None) if !call && Value { n: v.n.wrapping_sub(self.here() + 2), ..v }.short() => {
+                self.out.push(0xEB);
+                self.rel8(v)?;
+            }
+            (Arg::Imm(v), None | Some(Hint::Near)) => {
+                self.out.push(if call { 0xE8 } else { 0xE9 });
+                self.rel16(v)?;
+            }
+            (Arg::Far(seg, off), None | Some(Hint::Far)) => {
+                self.out.push(if call { 0x9A } else { 0xEA });
+                self.imm(off, Width::Word)?;
+                self.imm(seg, Width::Word)?;
+            }
+            (Arg::Reg(Width::Word, _), None | Some(Hint::Near | Hint::Word)) | (Arg::Mem(_), None | Some(Hint::Near | Hint::Word)) => {
+                self.out.push(0xFF);
+                self.modrm(if call { 2 } else { 4 }, &arg)?;
+            }
+            (Arg::Mem(_), Some(Hint::Far)) => {
+                self.out.push(0xFF);
+                self.modrm(if call { 3 } else { 5 }, &arg)?;
+            }
+            _ => return Err(format!("invalid operands for {}", name)),
+        }
+        Ok(())
+    }
+}
+
+// the rest of the instruction set, for telling bad operands from a typo
+const MNEMONICS: [&str; 20] = [
+    "inc", "dec", "push", "pop", "lea", "lds", "les", "in", "out", "int",
+    "aam", "aad", "ret", "retf", "loop", "loope", "loopz", "loopne", "loopnz", "esc",
+];
+
+// the operand size: from a register operand, else from a size given on memory
+fn width(ops: &[Operand]) -> Result<Width, String> {
+    let mut width = None;
+    for o in ops {
+        let this = match (o.arg, o.hint) {
+            (Arg::Reg(w, _), _) => w,
+            (Arg::Seg(_), _) => Width::Word,
+            (_, Some(Hint::Byte)) => Width::Byte,
+            (_, Some(Hint::Word)) => Width::Word,
+            _ => continue,
+        };
+        match width {
+            Some(w) if w != this => return Err("operand sizes do not match".into()),
+            _ => width = Some(this),
+        }
+    }
+    width.ok_or_else(|| "operation size not specified".into())
+}
+
+fn describe(tok: &Tok) -> String {
+    match tok {
+        Tok::Ident(s) => s.clone(),
+        Tok::Num(n) => n.to_string(),
+        Tok::Str(_) => "string".into(),
+        Tok::Punct(p) => format!("'{}'", p),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{disassemble, Syntax};
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap_or_else(|e| panic!("{}: {}", source, e)).bytes
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().message
+    }
+
+    #[test]
+    fn encodings() {
+        let cases: &[(&str, &[u8])] = &[
+            ("mov ax, [bp]", &[0x8B, 0x46, 0x00]),
+            ("mov al, [bx+si+5]", &[0x8A, 0x40, 0x05]),
+            ("mov [bp+di-0x100], dx", &[0x89, 0x93, 0x00, 0xFF]),
+            ("mov ax, [0x100]", &[0xA1, 0x00, 0x01]),
+            ("mov bx, 0x1234", &[0xBB, 0x34, 0x12]),
+            ("mov ds, ax", &[0x8E, 0xD8]),
+            ("mov byte [di], 'A'", &[0xC6, 0x05, 0x41]),
+            ("add ax, 0x1234", &[0x05, 0x34, 0x12]),
+            ("add word [bx], 1", &[0x83, 0x07, 0x01]),
+            ("cmp al, -1", &[0x3C, 0xFF]),
+            ("push word [bp-2]", &[0xFF, 0x76, 0xFE]),
+            ("shl bx, cl", &[0xD3, 0xE3]),
+            ("imul cx", &[0xF7, 0xE9]),
+            // segment overrides, inside or in front of the brackets
+            ("mov ax, es:[bx]", &[0x26, 0x8B, 0x07]),
+            ("mov [cs:si+2], al", &[0x2E, 0x88, 0x44, 0x02]),
+            ("cs lodsb", &[0x2E, 0xAC]),
+            ("rep movsb", &[0xF3, 0xA4]),
+            ("repne scasw", &[0xF2, 0xAF]),
+            ("jmp 0x1234:0x5678", &[0xEA, 0x78, 0x56, 0x34, 0x12]),
+            ("int 21h", &[0xCD, 0x21]),
+        ];
+        for &(source, expected) in cases {
+            assert_eq!(bytes(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn jump_sizes() {
+        // backward targets in reach get a short jump, forward ones a near
+        // jump unless marked short
+        let code = bytes("
+        start:
+            nop
+            jmp start
+            jmp fwd
+            jmp short fwd
+            je start
+            jne fwd
+        fwd:
+        ");
+        assert_eq!(code, [0x90, 0xEB, 0xFD, 0xE9, 0x06, 0x00, 0xEB, 0x04, 0x74, 0xF6, 0x75, 0x00]);
+
+        let code = bytes("start: times 200 nop\n jmp start\n call start");
+        assert_eq!(code[200..], [0xE9, 0x35, 0xFF, 0xE8, 0x32, 0xFF]);
+        assert_eq!(error("start: times 200 nop\n je start"), "jump target is -202 bytes away, out of short range");
+    }
+
+    #[test]
+    fn org_times_and_data() {
+        let asm = assemble("
+            org 100h
+        start: db 1, 'ab', 2
+            dw 0x1234, start, 'c'
+            times 3 db 0xFF
+        size: dw $ - $$
+        ").unwrap();
+        assert_eq!(asm.origin, 0x100);
+        assert_eq!(asm.bytes, [1, b'a', b'b', 2, 0x34, 0x12, 0x00, 0x01, b'c', 0, 0xFF, 0xFF, 0xFF, 0x0D, 0x00]);
+        assert_eq!((asm.labels["start"], asm.labels["size"]), (0x100, 0x10D));
+        assert_eq!(error("nop\norg 100h"), "org must come before any code or data");
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = [
+            "mov ax, [bp-0x2]",
+            "mov word [es:bx+0x4], 0x1",
+            "add byte [bx+si], 0x7f",
+            "xchg [di], cx",
+            "lea si, [bp+di+0x10]",
+            "in al, dx",
+            "out 0x60, al",
+            "rep stosw",
+            "lock inc word [0x100]",
+            "call 0x0",
+            "retf 0x4",
+        ];
+        let code = bytes(&source.join("\n"));
+        let text: Vec<_> = disassemble(&code, 0, Syntax::Nasm).into_iter().map(|l| l.text).collect();
+        assert_eq!(text, source);
+    }
+
+    #[test]
+    fn imul_needs_one_operand() {
+        assert_eq!(error("imul 5"), "invalid operands for imul");
+        assert_eq!(error("imul ax, 5"), "imul with more than one operand needs an 80186");
+        assert_eq!(error("imul ax, bx, 5"), "imul with more than one operand needs an 80186");
+    }
+}
@@ -0,0 +1,216 @@
+// Booting a floppy or hard-disk image the way a PC BIOS does, with just
+// enough INT 13h (disk) and INT 10h (teletype output) for a boot sector
+
+use std::fmt;
+use std::io::Write;
+
+use crate::bus::{physical, Bus};
+use crate::cpu::X86Cpu;
+use crate::flags::CF;
+
+pub const BOOT_SEGMENT: u16 = 0x0000;
+pub const BOOT_OFFSET: u16 = 0x7C00;
+
+// where the PC BIOS keeps the IRET its unused vectors point at
+const DUMMY_IRET: (u16, u16) = (0xF000, 0xFF53);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BootError {
+    /// The image is shorter than one sector.
+    TooShort,
+    /// Sector 0 does not end in 55h AAh.
+    NoSignature,
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootError::TooShort => write!(f, "image is shorter than a 512-byte sector"),
+            BootError::NoSignature => write!(f, "sector 0 has no 55AA boot signature"),
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+/// A disk image with the CHS geometry INT 13h addresses it by.
+#[derive(Clone, Debug)]
+pub struct Disk {
+    pub data: Vec<u8>,
+    pub drive: u8, // BIOS drive number: 00h for the first floppy, 80h for the first hard disk
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8, // per track
+}
+
+impl Disk {
+    /// Standard floppy sizes get their usual geometry and drive 00h; anything
+    /// else is taken as a hard disk with 16 heads of 63 sectors, drive 80h.
+    pub fn new(data: Vec<u8>) -> Disk {
+        let (drive, cylinders, heads, sectors) = match data.len() {
+            368_640 => (0x00, 40, 2, 9),
+            737_280 => (0x00, 80, 2, 9),
+            1_228_800 => (0x00, 80, 2, 15),
+            1_474_560 => (0x00, 80, 2, 18),
+            2_949_120 => (0x00, 80, 2, 36),
+            len => (0x80, len.div_ceil(16 * 63 * 512).clamp(1, 1024) as u16, 16, 63),
+        };
+        Disk { data, drive, cylinders, heads, sectors }
+    }
+
+    // byte offset of a CHS address, if it is on the disk
+    fn offset(&self, cylinder: u16, head: u8, sector: u8) -> Option<usize> {
+        if sector == 0 || sector > self.sectors || head >= self.heads || cylinder >= self.cylinders {
+            return None;
+        }
+        let lba = (cylinder as usize * self.heads as usize + head as usize) * self.sectors as usize + sector as usize - 1;
+        Some(lba * 512)
+    }
+}
+
+/// Load sector 0 of `disk` to 0000:7C00 and jump there with DL set to the
+/// boot drive, after pointing every interrupt vector at a dummy IRET and
+/// serving INT 13h from `disk` and INT 10h teletype output to `out`.
+pub fn boot<B: Bus + 'static>(cpu: &mut X86Cpu<B>, disk: Disk, out: impl Write + 'static) -> Result<(), BootError> {
+    if disk.data.len() < 512 {
+        return Err(BootError::TooShort);
+    }
+    if disk.data[510..512] != [0x55, 0xAA] {
+        return Err(BootError::NoSignature);
+    }
+    for vector in 0..256u32 {
+        let (seg, off) = DUMMY_IRET;
+        let [o0, o1] = off.to_le_bytes();
+        let [s0, s1] = seg.to_le_bytes();
+        cpu.load(vector * 4, &[o0, o1, s0, s1]);
+    }
+    cpu.load(physical(DUMMY_IRET.0, DUMMY_IRET.1), &[0xCF]);
+    cpu.load(physical(BOOT_SEGMENT, BOOT_OFFSET), &disk.data[..512]);
+
+    let r = &mut cpu.regs;
+    (r.cs, r.ip) = (BOOT_SEGMENT, BOOT_OFFSET);
+    (r.ds, r.es, r.ss, r.sp) = (0, 0, 0, BOOT_OFFSET);
+    r.dx = disk.drive as u16;
+    hook_disk(cpu, disk);
+    hook_teletype(cpu, out);
+    Ok(())
+}
+
+/// INT 13h for a single drive: reset (00h), status (01h), read (02h) and
+/// write (03h) sectors by CHS, and drive parameters (08h). Other functions
+/// and drives fail with CF set and AH=01h.
+pub fn hook_disk<B: Bus + 'static>(cpu: &mut X86Cpu<B>, mut disk: Disk) {
+    let mut last_status = 0u8;
+    cpu.hook_interrupt(0x13, move |cpu| {
+        let r = cpu.regs;
+        let (ah, al) = (r.get8(4), r.get8(0));
+        let cylinder = r.get8(5) as u16 | (r.get8(1) as u16 & 0xC0) << 2;
+        let (sector, head) = (r.get8(1) & 0x3F, r.get8(6));
+        let status = match ah {
+            _ if r.get8(2) != disk.drive => 0x01,
+            0x00 => 0x00,
+            0x01 => last_status,
+            0x02 | 0x03 => {
+                // a transfer runs on into the next head and cylinder, like a
+                // BIOS's multi-track reads, but has to end on the disk
+                match disk.offset(cylinder, head, sector) {
+                    Some(start) if al > 0 && start + al as usize * 512 <= disk.data.len() => {
+                        for i in 0..al as usize * 512 {
+                            let off = r.bx.wrapping_add(i as u16);
+                            if ah == 0x02 {
+                                cpu.write_mem8(r.es, off, disk.data[start + i]);
+                            } else {
+                                disk.data[start + i] = cpu.read_mem8(r.es, off);
+                            }
+                        }
+                        0x00
+                    }
+                    _ => 0x04, // sector not found
+                }
+            }
+            0x08 => {
+                let max_cyl = disk.cylinders - 1;
+                let drive_type = match (disk.drive, disk.sectors) {
+                    (0x80.., _) => 0,
+                    (_, 9) if disk.cylinders == 40 => 1, // 360K
+                    (_, 9) => 3,
+                    (_, 15) => 2,
+                    (_, 18) => 4,
+                    _ => 6,
+                };
+                cpu.regs.set8(3, drive_type);
+                cpu.regs.set8(5, max_cyl as u8);
+                cpu.regs.set8(1, disk.sectors | ((max_cyl >> 2) as u8 & 0xC0));
+                cpu.regs.set8(6, disk.heads - 1);
+                cpu.regs.set8(2, 1);
+                (cpu.regs.es, cpu.regs.di) = (0, 0);
+                0x00
+            }
+            _ => 0x01,
+        };
+        last_status = status;
+        cpu.regs.set8(4, status);
+        if matches!(ah, 0x02 | 0x03) && status != 0 {
+            cpu.regs.set8(0, 0);
+        }
+        cpu.set_flag(CF, status != 0);
+        true
+    });
+}
+
+/// INT 10h function 0Eh (teletype output) writes AL to `out`; other video
+/// functions do nothing.
+pub fn hook_teletype<B: Bus + 'static>(cpu: &mut X86Cpu<B>, mut out: impl Write + 'static) {
+    cpu.hook_interrupt(0x10, move |cpu| {
+        if cpu.regs.get8(4) == 0x0E {
+            let _ = out.write_all(&[cpu.regs.get8(0)]);
+            let _ = out.flush();
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Budget, StopReason};
+
+    // a 1.44 MB floppy whose sectors are filled with their LBA's low byte
+    fn floppy() -> Disk {
+        let data = (0..1_474_560 / 512).flat_map(|lba: usize| [lba as u8; 512]).collect();
+        Disk::new(data)
+    }
+
+    // run INT 13h with AX, CX and DX set and ES:BX at 1000:0000
+    fn int13(disk: Disk, ax: u16, cx: u16, dx: u16) -> X86Cpu {
+        let mut cpu = X86Cpu::new();
+        hook_disk(&mut cpu, disk);
+        cpu.load(0, &[0xCD, 0x13, 0xF4]); // int 13h; hlt
+        (cpu.regs.ax, cpu.regs.cx, cpu.regs.dx) = (ax, cx, dx);
+        (cpu.regs.es, cpu.regs.bx) = (0x1000, 0);
+        assert_eq!(cpu.run(Budget::Instructions(10)).stop, StopReason::Halted);
+        cpu
+    }
+
+    #[test]
+    fn reads_cross_into_the_next_track() {
+        // sectors 17 and 18 of cylinder 0 head 0, then sector 1 of head 1
+        let mut cpu = int13(floppy(), 0x0203, 0x0011, 0x0000);
+        assert_eq!((cpu.regs.ax, cpu.flag(CF)), (0x0003, false));
+        let firsts: Vec<u8> = (0..3).map(|i| cpu.read_mem8(0x1000, i * 512)).collect();
+        assert_eq!(firsts, [16, 17, 18]);
+        assert_eq!(cpu.read_mem8(0x1000, 3 * 512 - 1), 18);
+
+        // and from the last sector of head 1 into the next cylinder
+        let mut cpu = int13(floppy(), 0x0202, 0x0012, 0x0100);
+        assert_eq!((cpu.read_mem8(0x1000, 0), cpu.read_mem8(0x1000, 512)), (35, 36));
+    }
+
+    #[test]
+    fn reads_past_the_end_of_the_disk_fail() {
+        let cpu = int13(floppy(), 0x0202, 0x4F12, 0x0100);
+        assert_eq!((cpu.regs.ax, cpu.flag(CF)), (0x0400, true));
+        let cpu = int13(floppy(), 0x0201, 0x4F13, 0x0100);
+        assert_eq!((cpu.regs.ax, cpu.flag(CF)), (0x0400, true));
+    }
+}
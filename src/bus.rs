@@ -0,0 +1,195 @@
+// Everything the CPU can address: physical memory and the I/O port space.
+// Addresses are 20-bit physical addresses, already wrapped by the CPU.
+
+use std::ops::{Range, RangeInclusive};
+
+use crate::io::{IoDevice, PortMap};
+
+pub const ADDRESS_SPACE: usize = 0x100000; //     1 megabyte
+
+pub trait Bus {
+    fn read_u8(&mut self, addr: u32) -> u8;
+    fn write_u8(&mut self, addr: u32, val: u8);
+
+    fn read_u16(&mut self, addr: u32) -> u16 {
+        let low = self.read_u8(addr) as u16;
+        let high = self.read_u8(wrap(addr + 1)) as u16;
+        (high << 8) | low
+    }
+
+    fn write_u16(&mut self, addr: u32, val: u16) {
+        self.write_u8(addr, (val & 0xFF) as u8);
+        self.write_u8(wrap(addr + 1), (val >> 8) as u8);
+    }
+
+    // the address of an access the bus refused since the last call, checked
+    // by the CPU after every instruction
+    fn take_fault(&mut self) -> Option<u32> {
+        None
+    }
+
+    // nothing answers on the port bus by default, so reads float high
+    fn io_read_u8(&mut self, _port: u16) -> u8 {
+        0xFF
+    }
+
+    fn io_write_u8(&mut self, _port: u16, _val: u8) {}
+
+    fn io_read_u16(&mut self, port: u16) -> u16 {
+        let low = self.io_read_u8(port) as u16;
+        let high = self.io_read_u8(port.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    fn io_write_u16(&mut self, port: u16, val: u16) {
+        self.io_write_u8(port, (val & 0xFF) as u8);
+        self.io_write_u8(port.wrapping_add(1), (val >> 8) as u8);
+    }
+}
+
+/// Real mode: segment * 16 + offset, wrapping at 1 MiB like the 8086's 20 address lines.
+pub fn physical(seg: u16, off: u16) -> u32 {
+    wrap(((seg as u32) << 4) + off as u32)
+}
+
+fn wrap(addr: u32) -> u32 {
+    addr & (ADDRESS_SPACE as u32 - 1)
+}
+
+// Plain RAM filling the whole address space
+pub struct Ram {
+    data: Box<[u8; ADDRESS_SPACE]>,
+}
+
+impl Ram {
+    pub fn new() -> Self {
+        let data = vec![0; ADDRESS_SPACE].into_boxed_slice();
+        Ram { data: data.try_into().unwrap() }
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Ram {
+    fn read_u8(&mut self, addr: u32) -> u8 {
+        self.data[wrap(addr) as usize]
+    }
+
+    fn write_u8(&mut self, addr: u32, val: u8) {
+        self.data[wrap(addr) as usize] = val;
+    }
+}
+
+// A device that decodes a range of physical memory; offsets are relative
+// to the start of the mapping
+pub trait MmioDevice {
+    fn read(&mut self, offset: u32) -> u8;
+    fn write(&mut self, offset: u32, val: u8);
+}
+
+enum Region {
+    Rom(Vec<u8>),
+    Device(Box<dyn MmioDevice>),
+    Unmapped,
+}
+
+// RAM with ROM, devices and holes mapped over it. Later mappings take
+// precedence where ranges overlap.
+pub struct MemoryMap {
+    ram: Ram,
+    regions: Vec<(Range<u32>, Region)>,
+    pub ports: PortMap,
+    pub fault_unmapped: bool, // accesses to unmapped ranges stop the CPU with a memory fault
+    fault: Option<u32>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap { ram: Ram::new(), regions: Vec::new(), ports: PortMap::new(), fault_unmapped: false, fault: None }
+    }
+
+    // writes to ROM are ignored; reads past the end of `data` see open bus
+    pub fn map_rom(&mut self, start: u32, len: u32, data: Vec<u8>) {
+        self.regions.push((start..start + len, Region::Rom(data)));
+    }
+
+    pub fn map_device(&mut self, range: Range<u32>, device: Box<dyn MmioDevice>) {
+        self.regions.push((range, Region::Device(device)));
+    }
+
+    // nothing drives the data bus here: reads return 0xFF, writes are lost,
+    // unless `fault_unmapped` is set
+    pub fn unmap(&mut self, range: Range<u32>) {
+        self.regions.push((range, Region::Unmapped));
+    }
+
+    pub fn map_ports(&mut self, ports: RangeInclusive<u16>, device: Box<dyn IoDevice>) {
+        self.ports.map(ports, device);
+    }
+
+    fn region(&mut self, addr: u32) -> Option<(u32, &mut Region)> {
+        self.regions.iter_mut().rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(range, region)| (addr - range.start, region))
+    }
+
+    // the first fault of an instruction is the one reported
+    fn unmapped_access(&mut self, addr: u32) {
+        if self.fault_unmapped && self.fault.is_none() {
+            self.fault = Some(addr);
+        }
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read_u8(&mut self, addr: u32) -> u8 {
+        match self.region(addr) {
+            Some((off, Region::Rom(data))) => data.get(off as usize).copied().unwrap_or(0xFF),
+            Some((off, Region::Device(dev))) => dev.read(off),
+            Some((_, Region::Unmapped)) => {
+                self.unmapped_access(addr);
+                0xFF
+            }
+            None => self.ram.read_u8(addr),
+        }
+    }
+
+    fn write_u8(&mut self, addr: u32, val: u8) {
+        match self.region(addr) {
+            Some((off, Region::Device(dev))) => dev.write(off, val),
+            Some((_, Region::Unmapped)) => self.unmapped_access(addr),
+            Some(_) => {}
+            None => self.ram.write_u8(addr, val),
+        }
+    }
+
+    fn take_fault(&mut self) -> Option<u32> {
+        self.fault.take()
+    }
+
+    fn io_read_u8(&mut self, port: u16) -> u8 {
+        self.ports.read_u8(port)
+    }
+
+    fn io_write_u8(&mut self, port: u16, val: u8) {
+        self.ports.write_u8(port, val);
+    }
+
+    fn io_read_u16(&mut self, port: u16) -> u16 {
+        self.ports.read_u16(port)
+    }
+
+    fn io_write_u16(&mut self, port: u16, val: u16) {
+        self.ports.write_u16(port, val);
+    }
+}
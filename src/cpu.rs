@@ -0,0 +1,1141 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+use crate::bus::{physical, Bus, Ram, ADDRESS_SPACE};
+use crate::decode::{decode, Instruction, Op, Rep, Width};
+use crate::flags::{FlagOp, LazyFlags, AF, CF, DF, IF, OF, PF, SF, TF, ZF};
+use crate::modrm::{Address, Base, Operand};
+use crate::timing;
+
+const STACK_START: u16 = 0xFFF0;
+
+/// The general, segment and instruction pointer registers. FLAGS is kept by
+/// [`X86Cpu`] itself, see [`X86Cpu::flags`].
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub ax: u16, pub bx: u16, pub cx: u16, pub dx: u16,
+    pub si: u16, pub di: u16, pub sp: u16, pub bp: u16,
+    pub es: u16, pub cs: u16, pub ss: u16, pub ds: u16,
+    pub ip: u16,
+}
+
+impl Registers {
+    /// Word register by reg-field encoding: 0=AX 1=CX 2=DX 3=BX 4=SP 5=BP 6=SI 7=DI
+    pub fn get16(&self, r: u8) -> u16 {
+        match r & 7 {
+            0 => self.ax, 1 => self.cx, 2 => self.dx, 3 => self.bx,
+            4 => self.sp, 5 => self.bp, 6 => self.si, _ => self.di,
+        }
+    }
+
+    pub fn set16(&mut self, r: u8, val: u16) {
+        match r & 7 {
+            0 => self.ax = val, 1 => self.cx = val, 2 => self.dx = val, 3 => self.bx = val,
+            4 => self.sp = val, 5 => self.bp = val, 6 => self.si = val, _ => self.di = val,
+        }
+    }
+
+    /// Segment register by encoding: 0=ES 1=CS 2=SS 3=DS
+    pub fn get_seg(&self, r: u8) -> u16 {
+        match r & 3 { 0 => self.es, 1 => self.cs, 2 => self.ss, _ => self.ds }
+    }
+
+    pub fn set_seg(&mut self, r: u8, val: u16) {
+        match r & 3 { 0 => self.es = val, 1 => self.cs = val, 2 => self.ss = val, _ => self.ds = val }
+    }
+
+    /// Byte register by reg-field encoding: 0=AL 1=CL 2=DL 3=BL 4=AH 5=CH 6=DH 7=BH
+    pub fn get8(&self, r: u8) -> u8 {
+        let word = self.get16(r & 3);
+        if r & 4 == 0 { word as u8 } else { (word >> 8) as u8 }
+    }
+
+    pub fn set8(&mut self, r: u8, val: u8) {
+        let word = self.get16(r & 3);
+        let word = if r & 4 == 0 {
+            (word & 0xFF00) | val as u16
+        } else {
+            (word & 0x00FF) | (val as u16) << 8
+        };
+        self.set16(r & 3, word);
+    }
+}
+
+/// A real-mode 8086 attached to a [`Bus`], plain 1 MiB RAM by default.
+pub struct X86Cpu<B: Bus = Ram> {
+    pub regs: Registers,
+    pub bus: B,
+    /// Set by HLT; `step` returns [`StopReason::Halted`] until the host clears it.
+    pub halted: bool,
+    pub model: CpuModel,
+    /// Approximate 8086 clocks spent since the CPU was created.
+    pub cycles: u64,
+    flags: u16, // [ ...|O|D|I|T|S|Z|A|P|C ], arithmetic bits may be stale, see X86Cpu::flags
+    lazy: LazyFlags,
+    hooks: HashMap<u8, InterruptHook<B>>,
+    stop_exceptions: u8,           // bit per Exception vector
+    stop: Option<StopReason>,      // why the current instruction stopped the CPU
+    breakpoints: HashSet<u32>,     // physical addresses
+    watchpoints: Vec<Watchpoint>,
+    watch_log: Vec<WatchHit>,      // hits on watchpoints that do not stop
+    at: (u16, u16),                // CS:IP of the instruction being executed
+    mid_rep: bool,                 // the last step left a repeated string instruction unfinished
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuModel {
+    /// Undefined opcodes stop the CPU with [`StopReason::UnknownOpcode`].
+    #[default]
+    I8086,
+    /// Undefined opcodes raise #UD; the 186's new instructions are not implemented.
+    I80186,
+}
+
+/// Exceptions the CPU raises itself, numbered by their interrupt vector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    DivideError = 0,
+    SingleStep = 1,
+    Breakpoint = 3,
+    Overflow = 4,
+    InvalidOpcode = 6,
+}
+
+/// Why [`X86Cpu::step`] stopped instead of completing an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// HLT was executed, or the CPU was already halted.
+    Halted,
+    /// An undefined opcode on the 8086 model. `addr` is the physical address
+    /// of the instruction, which CS:IP still points at.
+    UnknownOpcode { addr: u32, bytes: Vec<u8> },
+    /// INT3 when stopping on [`Exception::Breakpoint`], with IP past the INT3,
+    /// or a breakpoint set with [`X86Cpu::add_breakpoint`], with IP at it.
+    Breakpoint,
+    /// Any other exception the host asked to stop on, see
+    /// [`X86Cpu::stop_on_exception`]. IP is where the guest handler would return to.
+    Exception(Exception),
+    /// The bus rejected an access to physical address `addr`, see
+    /// [`Bus::take_fault`]. The registers and FLAGS are put back as they were
+    /// before the faulting instruction, so CS:IP is left at it, but memory it
+    /// wrote before the fault keeps the new values.
+    MemoryFault { addr: u32 },
+    /// An instruction accessed a range watched by a stopping [`Watchpoint`].
+    /// The access has happened and IP is past the instruction.
+    Watchpoint(WatchHit),
+    /// [`X86Cpu::run`] used up its [`Budget`].
+    BudgetExhausted,
+    /// The predicate passed to [`X86Cpu::run_until`] returned true.
+    Condition,
+}
+
+/// A kind of memory access, see [`Watchpoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Fetching an instruction to execute it.
+    Execute,
+}
+
+/// Watch `range` of physical memory for one kind of access, see
+/// [`X86Cpu::add_watchpoint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<u32>,
+    pub access: Access,
+    /// Stop the run after the accessing instruction; otherwise the hit is only
+    /// kept for [`X86Cpu::take_watch_hits`].
+    pub stop: bool,
+}
+
+/// An access to a watched range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub access: Access,
+    /// Physical address of the access.
+    pub addr: u32,
+    /// Bytes accessed: 1 or 2, or the instruction's length for an execute.
+    pub len: u16,
+    /// The value before and after the access; they differ only for a write.
+    /// For an execute, both are the opcode.
+    pub old: u16,
+    pub new: u16,
+    /// CS:IP of the accessing instruction.
+    pub cs: u16,
+    pub ip: u16,
+}
+
+/// What [`X86Cpu::run`] may spend before it stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    Unlimited,
+    /// Retired instructions; each iteration of a repeated string instruction counts as one.
+    Instructions(u64),
+    /// Approximate 8086 clocks, see [`X86Cpu::cycles`]. The instruction that
+    /// crosses the limit is completed.
+    Cycles(u64),
+}
+
+/// How a [`X86Cpu::run`] ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub stop: StopReason,
+    /// Instructions completed, including one that stopped the run after it
+    /// finished (INT3, a watchpoint, a trap).
+    pub retired: u64,
+    pub cycles: u64,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::UnknownOpcode { addr, bytes } => {
+                write!(f, "unknown opcode at 0x{:05X}:", addr)?;
+                bytes.iter().try_for_each(|b| write!(f, " {:02X}", b))
+            }
+            StopReason::Breakpoint => write!(f, "breakpoint"),
+            StopReason::Exception(exc) => write!(f, "{:?} exception", exc),
+            StopReason::MemoryFault { addr } => write!(f, "memory fault at 0x{:05X}", addr),
+            StopReason::Watchpoint(hit) => write!(f, "watchpoint: {}", hit),
+            StopReason::BudgetExhausted => write!(f, "budget exhausted"),
+            StopReason::Condition => write!(f, "stop condition met"),
+        }
+    }
+}
+
+impl std::error::Error for StopReason {}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        })
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (old, new) = match self.len {
+            1 => (format!("{:02X}", self.old), format!("{:02X}", self.new)),
+            _ => (format!("{:04X}", self.old), format!("{:04X}", self.new)),
+        };
+        match self.access {
+            Access::Execute => write!(f, "execute at 0x{:05X} ({:04X}:{:04X})", self.addr, self.cs, self.ip),
+            Access::Read => write!(f, "read of {} at 0x{:05X} by {:04X}:{:04X}: {}",
+                                   self.len, self.addr, self.cs, self.ip, old),
+            Access::Write => write!(f, "write of {} at 0x{:05X} by {:04X}:{:04X}: {} -> {}",
+                                    self.len, self.addr, self.cs, self.ip, old, new),
+        }
+    }
+}
+
+// Host code standing in for a guest interrupt handler, see X86Cpu::hook_interrupt
+type InterruptHook<B> = Box<dyn FnMut(&mut X86Cpu<B>) -> bool>;
+
+impl X86Cpu {
+    /// An 8086 with zeroed RAM and registers, SP at 0xFFF0.
+    pub fn new() -> Self {
+        Self::with_bus(Ram::new())
+    }
+}
+
+impl Default for X86Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: Bus> X86Cpu<B> {
+    pub fn with_bus(bus: B) -> Self {
+        let mut cpu = X86Cpu {
+            regs: Registers::default(),
+            bus,
+            halted: false,
+            model: CpuModel::I8086,
+            cycles: 0,
+            flags: 0,
+            lazy: LazyFlags::NONE,
+            hooks: HashMap::new(),
+            stop_exceptions: 0,
+            stop: None,
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            watch_log: Vec::new(),
+            at: (0, 0),
+            mid_rep: false,
+        };
+        cpu.regs.sp = STACK_START;
+        cpu
+    }
+
+    /// Copy `image` into memory starting at physical address `addr`.
+    pub fn load(&mut self, addr: u32, image: &[u8]) {
+        for (i, &byte) in image.iter().enumerate() {
+            self.bus.write_u8((addr + i as u32) & (ADDRESS_SPACE as u32 - 1), byte);
+        }
+    }
+
+    fn fetch(&mut self) -> Instruction {
+        self.decode_at(self.regs.cs, self.regs.ip)
+    }
+
+    /// Decode the instruction at `cs:ip` without executing it.
+    pub fn decode_at(&mut self, cs: u16, ip: u16) -> Instruction {
+        let mut pc = ip;
+        let bus = &mut self.bus;
+        decode(ip, || {
+            let b = bus.read_u8(physical(cs, pc));
+            pc = pc.wrapping_add(1);
+            b
+        })
+    }
+
+    // segment and offset of a memory operand
+    fn address(&self, addr: Address) -> (u16, u16) {
+        let seg = match (addr.seg, addr.base) {
+            (Some(s), _) => s,
+            (None, Base::BpSi | Base::BpDi | Base::Bp) => 2,
+            (None, _) => 3,
+        };
+        (self.regs.get_seg(seg), self.effective_address(addr))
+    }
+
+    fn effective_address(&self, addr: Address) -> u16 {
+        let r = &self.regs;
+        let base = match addr.base {
+            Base::BxSi => r.bx.wrapping_add(r.si),
+            Base::BxDi => r.bx.wrapping_add(r.di),
+            Base::BpSi => r.bp.wrapping_add(r.si),
+            Base::BpDi => r.bp.wrapping_add(r.di),
+            Base::Si => r.si,
+            Base::Di => r.di,
+            Base::Bp => r.bp,
+            Base::Bx => r.bx,
+            Base::None => 0,
+        };
+        base.wrapping_add(addr.disp)
+    }
+
+    /// Read guest memory as the CPU does, so read watchpoints see it.
+    pub fn read_mem8(&mut self, seg: u16, off: u16) -> u8 {
+        let addr = physical(seg, off);
+        let val = self.bus.read_u8(addr);
+        if self.watched(Access::Read, addr, 1) {
+            self.watch_hit(Access::Read, addr, 1, val as u16, val as u16);
+        }
+        val
+    }
+
+    pub fn write_mem8(&mut self, seg: u16, off: u16, val: u8) {
+        let addr = physical(seg, off);
+        if self.watched(Access::Write, addr, 1) {
+            let old = self.bus.read_u8(addr);
+            self.watch_hit(Access::Write, addr, 1, old as u16, val as u16);
+        }
+        self.bus.write_u8(addr, val);
+    }
+
+    /// A word at offset 0xFFFF wraps around to the start of the segment.
+    pub fn read_mem16(&mut self, seg: u16, off: u16) -> u16 {
+        if off == 0xFFFF {
+            let low = self.read_mem8(seg, off) as u16;
+            let high = self.read_mem8(seg, 0) as u16;
+            return (high << 8) | low;
+        }
+        let addr = physical(seg, off);
+        let val = self.bus.read_u16(addr);
+        if self.watched(Access::Read, addr, 2) {
+            self.watch_hit(Access::Read, addr, 2, val, val);
+        }
+        val
+    }
+
+    pub fn write_mem16(&mut self, seg: u16, off: u16, val: u16) {
+        if off == 0xFFFF {
+            self.write_mem8(seg, off, (val & 0xFF) as u8);
+            self.write_mem8(seg, 0, (val >> 8) as u8);
+            return;
+        }
+        let addr = physical(seg, off);
+        if self.watched(Access::Write, addr, 2) {
+            let old = self.bus.read_u16(addr);
+            self.watch_hit(Access::Write, addr, 2, old, val);
+        }
+        self.bus.write_u16(addr, val);
+    }
+
+    fn watched(&self, access: Access, addr: u32, len: u16) -> bool {
+        !self.watchpoints.is_empty() && self.watchpoints.iter().any(|w| w.access == access
+            && w.range.start < addr + len as u32 && addr < w.range.end)
+    }
+
+    // log the hit, or stop on it, or both when watchpoints overlap
+    fn watch_hit(&mut self, access: Access, addr: u32, len: u16, old: u16, new: u16) {
+        let (cs, ip) = self.at;
+        let hit = WatchHit { access, addr, len, old, new, cs, ip };
+        let (stop, log) = self.watchpoints.iter()
+            .filter(|w| w.access == access && w.range.start < addr + len as u32 && addr < w.range.end)
+            .fold((false, false), |(stop, log), w| (stop || w.stop, log || !w.stop));
+        if log {
+            self.watch_log.push(hit);
+        }
+        if stop && self.stop.is_none() {
+            self.stop = Some(StopReason::Watchpoint(hit));
+        }
+    }
+
+    fn read(&mut self, op: Operand, w: Width) -> u16 {
+        match (op, w) {
+            (Operand::Reg(r), Width::Byte) => self.regs.get8(r) as u16,
+            (Operand::Reg(r), Width::Word) => self.regs.get16(r),
+            (Operand::Seg(r), _) => self.regs.get_seg(r),
+            (Operand::Mem(addr), Width::Byte) => { let (seg, off) = self.address(addr); self.read_mem8(seg, off) as u16 }
+            (Operand::Mem(addr), Width::Word) => { let (seg, off) = self.address(addr); self.read_mem16(seg, off) }
+            (Operand::Imm(v) | Operand::Rel(v), _) => v,
+            (Operand::Cl, _) => self.regs.get8(1) as u16,
+            (Operand::Dx, _) => self.regs.dx,
+            (Operand::None | Operand::Far(..), _) => unreachable!("{:?} is not a value operand", op),
+        }
+    }
+
+    fn write(&mut self, op: Operand, w: Width, val: u16) {
+        match (op, w) {
+            (Operand::Reg(r), Width::Byte) => self.regs.set8(r, val as u8),
+            (Operand::Reg(r), Width::Word) => self.regs.set16(r, val),
+            (Operand::Seg(r), _) => self.regs.set_seg(r, val),
+            (Operand::Mem(addr), Width::Byte) => { let (seg, off) = self.address(addr); self.write_mem8(seg, off, val as u8) }
+            (Operand::Mem(addr), Width::Word) => { let (seg, off) = self.address(addr); self.write_mem16(seg, off, val) }
+            _ => unreachable!("{:?} is not writable", op),
+        }
+    }
+
+    fn read16(&mut self, op: Operand) -> u16 {
+        self.read(op, Width::Word)
+    }
+
+    fn write16(&mut self, op: Operand, val: u16) {
+        self.write(op, Width::Word, val)
+    }
+
+    // segment:offset of a far pointer, either immediate or stored offset-first in memory
+    fn read_far(&mut self, op: Operand) -> (u16, u16) {
+        match op {
+            Operand::Far(seg, off) => (seg, off),
+            Operand::Mem(addr) => {
+                let (seg, off) = self.address(addr);
+                (self.read_mem16(seg, off.wrapping_add(2)), self.read_mem16(seg, off))
+            }
+            _ => unreachable!("{:?} is not a far pointer", op),
+        }
+    }
+
+    pub fn push(&mut self, val: u16) {
+        self.regs.sp = self.regs.sp.wrapping_sub(2);
+        self.write_mem16(self.regs.ss, self.regs.sp, val);
+    }
+
+    pub fn pop(&mut self) -> u16 {
+        let val = self.read_mem16(self.regs.ss, self.regs.sp);
+        self.regs.sp = self.regs.sp.wrapping_add(2);
+        val
+    }
+
+    /// Whether FLAGS bit `f` (one of the [`crate::flags`] constants) is set.
+    pub fn flag(&self, f: u16) -> bool {
+        self.lazy.get(f, self.flags)
+    }
+
+    /// The FLAGS register. Arithmetic flags are computed on demand from the
+    /// last ALU result rather than after every instruction.
+    pub fn flags(&self) -> u16 {
+        self.lazy.apply(self.flags)
+    }
+
+    pub fn set_flags(&mut self, val: u16) {
+        self.lazy.op = FlagOp::None;
+        self.flags = val;
+    }
+
+    pub fn set_flag(&mut self, f: u16, on: bool) {
+        let flags = self.flags();
+        self.set_flags(if on { flags | f } else { flags & !f });
+    }
+
+    fn set_cf_of(&mut self, on: bool) {
+        let flags = self.flags() & !(CF | OF);
+        self.set_flags(if on { flags | CF | OF } else { flags });
+    }
+
+    fn set_szp(&mut self, val: u16, w: Width) {
+        self.set_flag(ZF, val & w.mask() == 0);
+        self.set_flag(SF, val & w.sign() != 0);
+        self.set_flag(PF, (val as u8).count_ones() & 1 == 0);
+    }
+
+    // condition codes in Jcc order: O NO B AE E NE BE A S NS P NP L GE LE G
+    fn condition(&self, cc: u8) -> bool {
+        let res = match cc >> 1 {
+            0 => self.flag(OF),
+            1 => self.flag(CF),
+            2 => self.flag(ZF),
+            3 => self.flag(CF) || self.flag(ZF),
+            4 => self.flag(SF),
+            5 => self.flag(PF),
+            6 => self.flag(SF) != self.flag(OF),
+            _ => self.flag(ZF) || self.flag(SF) != self.flag(OF),
+        };
+        res != (cc & 1 != 0)
+    }
+
+    fn alu(&mut self, op: Op, w: Width, a: u16, b: u16) -> u16 {
+        let (flag_op, carry, res) = match op {
+            Op::Add => (FlagOp::Add, 0, a.wrapping_add(b)),
+            Op::Adc => {
+                let c = self.flag(CF) as u16;
+                (FlagOp::Add, c, a.wrapping_add(b).wrapping_add(c))
+            }
+            Op::Sub | Op::Cmp => (FlagOp::Sub, 0, a.wrapping_sub(b)),
+            Op::Sbb => {
+                let c = self.flag(CF) as u16;
+                (FlagOp::Sub, c, a.wrapping_sub(b).wrapping_sub(c))
+            }
+            Op::Or => (FlagOp::Logic, 0, a | b),
+            Op::And | Op::Test => (FlagOp::Logic, 0, a & b),
+            Op::Xor => (FlagOp::Logic, 0, a ^ b),
+            _ => unreachable!("{:?} is not an ALU op", op),
+        };
+        let res = res & w.mask();
+        self.lazy = LazyFlags { op: flag_op, width: w, a, b, carry, res };
+        self.eager_flags();
+        res
+    }
+
+    // INC/DEC leave CF untouched, so only that bit of the previous result is kept
+    fn incdec(&mut self, op: Op, w: Width, val: u16) -> u16 {
+        let cf = self.flag(CF) as u16;
+        self.flags = (self.flags & !CF) | cf;
+        let (flag_op, res) = if op == Op::Inc {
+            (FlagOp::Inc, val.wrapping_add(1) & w.mask())
+        } else {
+            (FlagOp::Dec, val.wrapping_sub(1) & w.mask())
+        };
+        self.lazy = LazyFlags { op: flag_op, width: w, a: val, b: 1, carry: 0, res };
+        self.eager_flags();
+        res
+    }
+
+    // with the eager-flags feature every result is turned into FLAGS at once,
+    // as a baseline for the benchmark
+    fn eager_flags(&mut self) {
+        if cfg!(feature = "eager-flags") {
+            self.set_flags(self.flags());
+        }
+    }
+
+    // the 8086 shifts one bit per microcode iteration, so for counts > 1
+    // OF reflects the final iteration
+    fn shift(&mut self, op: Op, w: Width, val: u16, count: u16) -> u16 {
+        let (msb, mask) = (w.sign(), w.mask());
+        let mut v = val;
+        // only RCL/RCR shift CF in; a count of 0 leaves CF and OF alone
+        let mut carry = matches!(op, Op::Rcl | Op::Rcr) && self.flag(CF);
+        let mut overflow = false;
+        for _ in 0..count {
+            let prev = v;
+            match op {
+                Op::Rol => { carry = v & msb != 0; v = (v << 1 | carry as u16) & mask; }
+                Op::Ror => { carry = v & 1 != 0; v = v >> 1 | if carry { msb } else { 0 }; }
+                Op::Rcl => { let out = v & msb != 0; v = (v << 1 | carry as u16) & mask; carry = out; }
+                Op::Rcr => { let out = v & 1 != 0; v = v >> 1 | if carry { msb } else { 0 }; carry = out; }
+                Op::Shl => { carry = v & msb != 0; v = (v << 1) & mask; }
+                Op::Shr => { carry = v & 1 != 0; v >>= 1; }
+                _ => { carry = v & 1 != 0; v = v >> 1 | (v & msb); }
+            }
+            overflow = (prev ^ v) & msb != 0;
+        }
+        if count != 0 {
+            self.set_flag(CF, carry);
+            self.set_flag(OF, overflow);
+            if matches!(op, Op::Shl | Op::Shr | Op::Sar) {
+                self.set_szp(v, w);
+            }
+        }
+        v
+    }
+
+    // DAA/DAS: adjust AL after a packed BCD add or subtract
+    fn decimal_adjust(&mut self, op: Op) {
+        let al = self.regs.get8(0);
+        let (cf, af) = (self.flag(CF), self.flag(AF));
+        let mut v = al;
+        let low = al & 0x0F > 9 || af;
+        // a carry or borrow out of the low adjust sets CF too
+        let mut carry = false;
+        if low {
+            (v, carry) = if op == Op::Daa { v.overflowing_add(0x06) } else { v.overflowing_sub(0x06) };
+        }
+        let high = al > 0x99 || cf;
+        if high {
+            v = if op == Op::Daa { v.wrapping_add(0x60) } else { v.wrapping_sub(0x60) };
+        }
+        self.regs.set8(0, v);
+        self.set_flag(AF, low);
+        self.set_flag(CF, high || carry);
+        self.set_szp(v as u16, Width::Byte);
+    }
+
+    // AAA/AAS: adjust AL after an unpacked BCD add or subtract, carrying into AH
+    fn ascii_adjust(&mut self, op: Op) {
+        let adjust = self.regs.get8(0) & 0x0F > 9 || self.flag(AF);
+        if adjust {
+            let (al, ah) = (self.regs.get8(0), self.regs.get8(4));
+            if op == Op::Aaa {
+                self.regs.set8(0, al.wrapping_add(6));
+                self.regs.set8(4, ah.wrapping_add(1));
+            } else {
+                self.regs.set8(0, al.wrapping_sub(6));
+                self.regs.set8(4, ah.wrapping_sub(1));
+            }
+        }
+        self.regs.set8(0, self.regs.get8(0) & 0x0F);
+        self.set_flag(AF, adjust);
+        self.set_flag(CF, adjust);
+    }
+
+    /// Run `hook` in place of `INT vector`, standing in for a guest handler.
+    /// It returns true if it handled the call, or false to fall through to
+    /// the handler in the interrupt vector table.
+    pub fn hook_interrupt(&mut self, vector: u8, hook: impl FnMut(&mut X86Cpu<B>) -> bool + 'static) {
+        self.hooks.insert(vector, Box::new(hook));
+    }
+
+    pub fn unhook_interrupt(&mut self, vector: u8) {
+        self.hooks.remove(&vector);
+    }
+
+    /// Real-mode interrupt: push FLAGS, CS and IP, then jump through the
+    /// vector table at 0000:0000. Hosts call this to deliver hardware interrupts.
+    pub fn interrupt(&mut self, vector: u8) {
+        if let Some(mut hook) = self.hooks.remove(&vector) {
+            let handled = hook(self);
+            self.hooks.entry(vector).or_insert(hook);
+            if handled {
+                return;
+            }
+        }
+        let flags = self.flags() | 0xF002;
+        self.push(flags);
+        self.set_flag(IF, false);
+        self.set_flag(TF, false);
+        self.push(self.regs.cs);
+        self.push(self.regs.ip);
+        let entry = vector as u16 * 4;
+        self.regs.ip = self.read_mem16(0, entry);
+        self.regs.cs = self.read_mem16(0, entry + 2);
+    }
+
+    /// Stop with `exc` as the [`StopReason`] instead of entering the guest handler.
+    pub fn stop_on_exception(&mut self, exc: Exception, stop: bool) {
+        let bit = 1 << exc as u8;
+        if stop { self.stop_exceptions |= bit } else { self.stop_exceptions &= !bit }
+    }
+
+    // IP is left where the guest handler would return to: after the
+    // instruction for #DE (as on the 8086) and traps, at it for #UD
+    fn raise(&mut self, exc: Exception) {
+        if self.stop_exceptions & (1 << exc as u8) != 0 {
+            self.stop = Some(match exc {
+                Exception::Breakpoint => StopReason::Breakpoint,
+                _ => StopReason::Exception(exc),
+            });
+            return;
+        }
+        self.interrupt(exc as u8);
+    }
+
+    /// Stop [`X86Cpu::run`] before executing the instruction at physical address `addr`.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Watch for instructions accessing a range of physical memory. Reads
+    /// and writes by host code through [`X86Cpu::read_mem8`] and the like
+    /// count too, as coming from the current instruction; [`X86Cpu::load`]
+    /// does not.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Remove one watchpoint equal to `watchpoint`.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) {
+        if let Some(i) = self.watchpoints.iter().position(|w| w == watchpoint) {
+            self.watchpoints.remove(i);
+        }
+    }
+
+    /// Hits on watchpoints that do not stop, oldest first, since the last call.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_log)
+    }
+
+    /// Whether the last step left a repeated string instruction unfinished,
+    /// with CS:IP still at it.
+    pub fn mid_rep(&self) -> bool {
+        self.mid_rep
+    }
+
+    /// Step until `budget` is spent or something stops the CPU.
+    pub fn run(&mut self, budget: Budget) -> RunOutcome {
+        self.run_until(budget, |_| false)
+    }
+
+    /// Like [`X86Cpu::run`], also stopping once `until` returns true after an
+    /// instruction. A breakpoint at the starting CS:IP is not reported, so
+    /// running again continues past it.
+    pub fn run_until(&mut self, budget: Budget, mut until: impl FnMut(&mut X86Cpu<B>) -> bool) -> RunOutcome {
+        let start = self.cycles;
+        let mut retired = 0;
+        let mut first = true;
+        let stop = loop {
+            let spent = match budget {
+                Budget::Unlimited => false,
+                Budget::Instructions(n) => retired >= n,
+                Budget::Cycles(n) => self.cycles - start >= n,
+            };
+            if spent {
+                break StopReason::BudgetExhausted;
+            }
+            if !first && !self.mid_rep && !self.breakpoints.is_empty()
+                && self.breakpoints.contains(&physical(self.regs.cs, self.regs.ip)) {
+                break StopReason::Breakpoint;
+            }
+            first = false;
+            match self.step() {
+                Ok(()) => retired += 1,
+                Err(reason) => {
+                    let completed = matches!(reason,
+                        StopReason::Breakpoint | StopReason::Watchpoint(_)
+                        | StopReason::Exception(Exception::SingleStep | Exception::Overflow));
+                    if completed {
+                        retired += 1;
+                    }
+                    break reason;
+                }
+            }
+            if until(self) {
+                break StopReason::Condition;
+            }
+        };
+        RunOutcome { stop, retired, cycles: self.cycles - start }
+    }
+
+    /// Execute one instruction, or one iteration of a repeated string instruction.
+    pub fn step(&mut self) -> Result<(), StopReason> {
+        if self.halted {
+            return Err(StopReason::Halted);
+        }
+        // the trap follows the instruction that started with TF set, so one
+        // that clears TF is still trapped and one that sets it is not
+        let trap = self.flag(TF);
+        let insn = self.fetch();
+        let (cs, ip) = (self.regs.cs, self.regs.ip);
+        let (cl, cx) = (self.regs.get8(1), self.regs.cx);
+        // to undo a faulting instruction's register and flag changes
+        let before = (self.regs, self.flags, self.lazy, self.mid_rep);
+        self.at = (cs, ip);
+        // each iteration of a repeated string instruction is not a new fetch
+        if !self.mid_rep && self.watched(Access::Execute, physical(cs, ip), insn.len) {
+            let opcode = insn.opcode as u16;
+            self.watch_hit(Access::Execute, physical(cs, ip), insn.len, opcode, opcode);
+        }
+        let next = ip.wrapping_add(insn.len);
+        self.regs.ip = next;
+        self.mid_rep = false;
+        self.execute(insn, ip);
+        let taken = (self.regs.cs, self.regs.ip) != (cs, next);
+        self.cycles += timing::clocks(&insn, cl, cx, taken) as u64;
+        if let Some(addr) = self.bus.take_fault() {
+            (self.regs, self.flags, self.lazy, self.mid_rep) = before;
+            self.stop = None;
+            return Err(StopReason::MemoryFault { addr });
+        }
+        // INT clears TF on entry and the handler runs untraced
+        if trap && self.stop.is_none() && !matches!(insn.op, Op::Int | Op::Int3 | Op::Into) {
+            self.raise(Exception::SingleStep);
+        }
+        self.stop.take().map_or(Ok(()), Err)
+    }
+
+    fn execute(&mut self, insn: Instruction, ip: u16) {
+        let Instruction { op, width: w, dst, src, .. } = insn;
+        match op {
+            Op::Add | Op::Or | Op::Adc | Op::Sbb | Op::And | Op::Sub | Op::Xor => {
+                let (a, b) = (self.read(dst, w), self.read(src, w));
+                let res = self.alu(op, w, a, b);
+                self.write(dst, w, res);
+            }
+            Op::Cmp | Op::Test => {
+                let (a, b) = (self.read(dst, w), self.read(src, w));
+                self.alu(op, w, a, b);
+            }
+
+            Op::Inc | Op::Dec => {
+                let v = self.read(dst, w);
+                let res = self.incdec(op, w, v);
+                self.write(dst, w, res);
+            }
+            Op::Not => { let v = self.read(dst, w); self.write(dst, w, !v); }
+            Op::Neg => {
+                let v = self.read(dst, w);
+                let res = self.alu(Op::Sub, w, 0, v);
+                self.write(dst, w, res);
+            }
+
+            Op::Mul if w == Width::Byte => {
+                self.regs.ax = self.regs.get8(0) as u16 * self.read(dst, w);
+                self.set_cf_of(self.regs.ax & 0xFF00 != 0);
+            }
+            Op::Mul => {
+                let res = (self.regs.ax as u32) * (self.read16(dst) as u32);
+                self.regs.ax = res as u16;
+                self.regs.dx = (res >> 16) as u16;
+                self.set_cf_of(self.regs.dx != 0);
+            }
+            // CF/OF are set when the high half is more than a sign extension of the low half
+            Op::Imul if w == Width::Byte => {
+                let res = (self.regs.get8(0) as i8 as i16) * (self.read(dst, w) as u8 as i8 as i16);
+                self.regs.ax = res as u16;
+                self.set_cf_of(res != res as i8 as i16);
+            }
+            Op::Imul => {
+                let res = (self.regs.ax as i16 as i32) * (self.read16(dst) as i16 as i32);
+                self.regs.ax = res as u16;
+                self.regs.dx = (res >> 16) as u16;
+                self.set_cf_of(res != res as i16 as i32);
+            }
+            Op::Div if w == Width::Byte => {
+                let num = self.regs.ax;
+                let div = self.read(dst, w);
+                if div == 0 || num / div > 0xFF {
+                    return self.raise(Exception::DivideError);
+                }
+                self.regs.set8(0, (num / div) as u8);
+                self.regs.set8(4, (num % div) as u8);
+            }
+            Op::Div => {
+                let num = (self.regs.dx as u32) << 16 | self.regs.ax as u32;
+                let div = self.read16(dst) as u32;
+                if div == 0 || num / div > 0xFFFF {
+                    return self.raise(Exception::DivideError);
+                }
+                self.regs.ax = (num / div) as u16;
+                self.regs.dx = (num % div) as u16;
+            }
+            // the 8086 faults on a quotient of -128 / -32768 as well
+            Op::Idiv if w == Width::Byte => {
+                let num = self.regs.ax as i16 as i32;
+                let div = self.read(dst, w) as u8 as i8 as i32;
+                if div == 0 || !(-0x7F..=0x7F).contains(&(num / div)) {
+                    return self.raise(Exception::DivideError);
+                }
+                self.regs.set8(0, (num / div) as u8);
+                self.regs.set8(4, (num % div) as u8);
+            }
+            Op::Idiv => {
+                let num = ((self.regs.dx as u32) << 16 | self.regs.ax as u32) as i32;
+                let div = self.read16(dst) as i16 as i32;
+                if div == 0 || !(-0x7FFF..=0x7FFF).contains(&(num as i64 / div as i64)) {
+                    return self.raise(Exception::DivideError);
+                }
+                self.regs.ax = (num / div) as u16;
+                self.regs.dx = (num % div) as u16;
+            }
+
+            Op::Rol | Op::Ror | Op::Rcl | Op::Rcr | Op::Shl | Op::Shr | Op::Sar => {
+                let (v, count) = (self.read(dst, w), self.read(src, w));
+                let res = self.shift(op, w, v, count);
+                self.write(dst, w, res);
+            }
+
+            Op::Daa | Op::Das => self.decimal_adjust(op),
+            Op::Aaa | Op::Aas => self.ascii_adjust(op),
+            Op::Aam => {
+                let base = self.read(dst, w) as u8;
+                if base == 0 {
+                    return self.raise(Exception::DivideError);
+                }
+                let al = self.regs.get8(0);
+                self.regs.set8(4, al / base);
+                self.regs.set8(0, al % base);
+                self.set_szp(self.regs.ax, Width::Byte);
+            }
+            Op::Aad => {
+                let base = self.read(dst, w) as u8;
+                let al = self.regs.get8(0).wrapping_add(self.regs.get8(4).wrapping_mul(base));
+                self.regs.ax = al as u16;
+                self.set_szp(self.regs.ax, Width::Byte);
+            }
+
+            Op::Mov => { let v = self.read(src, w); self.write(dst, w, v); }
+            Op::Xchg => {
+                let (a, b) = (self.read(dst, w), self.read(src, w));
+                self.write(dst, w, b);
+                self.write(src, w, a);
+            }
+            Op::Xlat => {
+                let Operand::Mem(addr) = src else { unreachable!() };
+                let (seg, off) = self.address(addr);
+                let v = self.read_mem8(seg, off.wrapping_add(self.regs.get8(0) as u16));
+                self.regs.set8(0, v);
+            }
+            Op::Lea => {
+                let Operand::Mem(addr) = src else { unreachable!() };
+                let ea = self.effective_address(addr);
+                self.write16(dst, ea);
+            }
+            Op::Lds | Op::Les => {
+                let (seg, off) = self.read_far(src);
+                self.write16(dst, off);
+                if op == Op::Lds { self.regs.ds = seg } else { self.regs.es = seg }
+            }
+
+            // PUSH SP stores the already decremented value on the 8086
+            Op::Push => {
+                let v = if dst == Operand::Reg(4) { self.regs.sp.wrapping_sub(2) } else { self.read16(dst) };
+                self.push(v);
+            }
+            Op::Pop => { let v = self.pop(); self.write16(dst, v); }
+            Op::Pushf => { let v = self.flags() | 0xF002; self.push(v); }
+            Op::Popf => { let v = self.pop() & 0x0FD5 | 0x0002; self.set_flags(v); }
+            Op::Lahf => { self.regs.set8(4, (self.flags() & 0xD5 | 0x02) as u8); }
+            Op::Sahf => {
+                let v = (self.flags() & 0xFF00) | self.regs.get8(4) as u16 & 0xD5 | 0x02;
+                self.set_flags(v);
+            }
+            Op::Cbw => { self.regs.ax = self.regs.get8(0) as i8 as u16; }
+            Op::Cwd => { self.regs.dx = if self.regs.ax & 0x8000 != 0 { 0xFFFF } else { 0 }; }
+
+            Op::Jmp => { self.regs.ip = self.read16(dst); }
+            Op::Jcc(cc) => {
+                if self.condition(cc) { self.regs.ip = self.read16(dst); }
+            }
+            Op::Loop | Op::Loopz | Op::Loopnz => {
+                self.regs.cx = self.regs.cx.wrapping_sub(1);
+                let taken = self.regs.cx != 0 && match op {
+                    Op::Loopz => self.flag(ZF),
+                    Op::Loopnz => !self.flag(ZF),
+                    _ => true,
+                };
+                if taken { self.regs.ip = self.read16(dst); }
+            }
+            Op::Jcxz => {
+                if self.regs.cx == 0 { self.regs.ip = self.read16(dst); }
+            }
+            Op::Call => {
+                let target = self.read16(dst);
+                self.push(self.regs.ip);
+                self.regs.ip = target;
+            }
+            Op::Ret => {
+                self.regs.ip = self.pop();
+                if dst != Operand::None { self.regs.sp = self.regs.sp.wrapping_add(self.read16(dst)); }
+            }
+            Op::JmpFar => {
+                let (seg, off) = self.read_far(dst);
+                self.regs.cs = seg;
+                self.regs.ip = off;
+            }
+            Op::CallFar => {
+                let (seg, off) = self.read_far(dst);
+                self.push(self.regs.cs);
+                self.push(self.regs.ip);
+                self.regs.cs = seg;
+                self.regs.ip = off;
+            }
+            Op::RetFar => {
+                self.regs.ip = self.pop();
+                self.regs.cs = self.pop();
+                if dst != Operand::None { self.regs.sp = self.regs.sp.wrapping_add(self.read16(dst)); }
+            }
+
+            Op::Int => {
+                let vector = self.read16(dst) as u8;
+                self.interrupt(vector);
+            }
+            Op::Int3 => self.raise(Exception::Breakpoint),
+            Op::Into => {
+                if self.flag(OF) { self.raise(Exception::Overflow); }
+            }
+            Op::Iret => {
+                self.regs.ip = self.pop();
+                self.regs.cs = self.pop();
+                let v = self.pop() & 0x0FD5 | 0x0002;
+                self.set_flags(v);
+            }
+
+            Op::Clc => self.set_flag(CF, false),
+            Op::Stc => self.set_flag(CF, true),
+            Op::Cmc => self.set_flag(CF, !self.flag(CF)),
+            Op::Cld => self.set_flag(DF, false),
+            Op::Std => self.set_flag(DF, true),
+            Op::Cli => self.set_flag(IF, false),
+            Op::Sti => self.set_flag(IF, true),
+
+            // one iteration per step, so a repeated string instruction can be
+            // interrupted and is resumed at its prefix with CX, SI and DI updated
+            Op::Movs | Op::Cmps | Op::Stos | Op::Lods | Op::Scas => {
+                if insn.rep.is_some() && self.regs.cx == 0 {
+                    return;
+                }
+                match op {
+                    Op::Cmps | Op::Scas => {
+                        let (a, b) = (self.read(dst, w), self.read(src, w));
+                        self.alu(Op::Cmp, w, a, b);
+                    }
+                    _ => { let v = self.read(src, w); self.write(dst, w, v); }
+                }
+                let step: u16 = if w == Width::Byte { 1 } else { 2 };
+                let delta = if self.flag(DF) { step.wrapping_neg() } else { step };
+                for o in [dst, src] {
+                    match o {
+                        Operand::Mem(Address { base: Base::Si, .. }) => self.regs.si = self.regs.si.wrapping_add(delta),
+                        Operand::Mem(Address { base: Base::Di, .. }) => self.regs.di = self.regs.di.wrapping_add(delta),
+                        _ => {}
+                    }
+                }
+                if let Some(rep) = insn.rep {
+                    self.regs.cx = self.regs.cx.wrapping_sub(1);
+                    let stop = matches!(op, Op::Cmps | Op::Scas) && self.flag(ZF) != (rep == Rep::Repe);
+                    if self.regs.cx != 0 && !stop {
+                        self.regs.ip = ip;
+                        self.mid_rep = true;
+                    }
+                }
+            }
+
+            Op::In => {
+                let port = self.read16(src);
+                let v = match w {
+                    Width::Byte => self.bus.io_read_u8(port) as u16,
+                    Width::Word => self.bus.io_read_u16(port),
+                };
+                self.write(dst, w, v);
+            }
+            Op::Out => {
+                let port = self.read16(dst);
+                let v = self.read(src, w);
+                match w {
+                    Width::Byte => self.bus.io_write_u8(port, v as u8),
+                    Width::Word => self.bus.io_write_u16(port, v),
+                }
+            }
+
+            Op::Nop | Op::Wait | Op::Esc => {}
+            Op::Hlt => {
+                self.halted = true;
+                self.stop = Some(StopReason::Halted);
+            }
+
+            Op::Invalid if self.model == CpuModel::I80186 => {
+                self.regs.ip = ip;
+                self.raise(Exception::InvalidOpcode);
+            }
+            Op::Invalid => {
+                self.regs.ip = ip;
+                let bytes = (0..insn.len).map(|i| self.bus.read_u8(physical(self.regs.cs, ip.wrapping_add(i)))).collect();
+                self.stop = Some(StopReason::UnknownOpcode { addr: physical(self.regs.cs, ip), bytes });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MemoryMap;
+
+    // run one BCD adjust on AX and CF/AF, giving AX, CF and AF after it
+    fn adjust(opcode: u8, ax: u16, cf: bool, af: bool) -> (u16, bool, bool) {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &[opcode]);
+        cpu.regs.ax = ax;
+        cpu.set_flag(CF, cf);
+        cpu.set_flag(AF, af);
+        cpu.step().unwrap();
+        (cpu.regs.ax, cpu.flag(CF), cpu.flag(AF))
+    }
+
+    const DAA: u8 = 0x27;
+    const DAS: u8 = 0x2F;
+    const AAA: u8 = 0x37;
+    const AAS: u8 = 0x3F;
+
+    #[test]
+    fn daa() {
+        assert_eq!(adjust(DAA, 0x0015, false, false), (0x0015, false, false));
+        assert_eq!(adjust(DAA, 0x0015, false, true), (0x001B, false, true));
+        assert_eq!(adjust(DAA, 0x000A, false, false), (0x0010, false, true));
+        assert_eq!(adjust(DAA, 0x009A, false, false), (0x0000, true, true));
+        assert_eq!(adjust(DAA, 0x00FA, false, false), (0x0060, true, true));
+        assert_eq!(adjust(DAA, 0x0012, true, false), (0x0072, true, false));
+    }
+
+    #[test]
+    fn das() {
+        assert_eq!(adjust(DAS, 0x0015, false, false), (0x0015, false, false));
+        assert_eq!(adjust(DAS, 0x0003, false, true), (0x00FD, true, true));
+        assert_eq!(adjust(DAS, 0x0000, true, false), (0x00A0, true, false));
+        assert_eq!(adjust(DAS, 0x009A, false, false), (0x0034, true, true));
+        assert_eq!(adjust(DAS, 0x001F, false, false), (0x0019, false, true));
+    }
+
+    #[test]
+    fn aaa() {
+        assert_eq!(adjust(AAA, 0x0039, false, false), (0x0009, false, false));
+        assert_eq!(adjust(AAA, 0x000F, false, false), (0x0105, true, true));
+        assert_eq!(adjust(AAA, 0x0032, false, true), (0x0108, true, true));
+        assert_eq!(adjust(AAA, 0x00FA, false, false), (0x0100, true, true));
+    }
+
+    #[test]
+    fn aas() {
+        assert_eq!(adjust(AAS, 0x0239, false, false), (0x0209, false, false));
+        assert_eq!(adjust(AAS, 0x0200, false, true), (0x010A, true, true));
+        assert_eq!(adjust(AAS, 0x000F, false, false), (0xFF09, true, true));
+    }
+
+    #[test]
+    fn a_segment_of_prefixes_stops() {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &[0x26; 0x10000]);
+        assert!(matches!(cpu.step(), Err(StopReason::UnknownOpcode { addr: 0, .. })));
+        cpu.model = CpuModel::I80186;
+        cpu.stop_on_exception(Exception::InvalidOpcode, true);
+        assert_eq!(cpu.step(), Err(StopReason::Exception(Exception::InvalidOpcode)));
+    }
+
+    #[test]
+    fn a_memory_fault_undoes_register_changes() {
+        let mut bus = MemoryMap::new();
+        bus.unmap(0x10000..0x20000);
+        bus.fault_unmapped = true;
+        let mut cpu = X86Cpu::with_bus(bus);
+        cpu.load(0, &[0x50, 0xFF, 0x04]); // push ax; inc word [si]
+        (cpu.regs.ss, cpu.regs.sp, cpu.regs.ds) = (0x1000, 0x10, 0x1000);
+        assert_eq!(cpu.step(), Err(StopReason::MemoryFault { addr: 0x1000E }));
+        assert_eq!((cpu.regs.sp, cpu.regs.ip), (0x10, 0));
+        cpu.regs.ip = 1;
+        let flags = cpu.flags();
+        assert_eq!(cpu.step(), Err(StopReason::MemoryFault { addr: 0x10000 }));
+        assert_eq!((cpu.flags(), cpu.regs.ip), (flags, 1));
+    }
+}
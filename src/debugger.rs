@@ -0,0 +1,737 @@
+// An interactive debugger over any line-based input and output: stepping,
+// breakpoints, registers and flags, memory, disassembly and the stack, in
+// the spirit of DOS DEBUG
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use crate::bus::{physical, Bus};
+use crate::cpu::{Access, Budget, StopReason, WatchHit, Watchpoint, X86Cpu};
+use crate::decode::Op;
+use crate::disasm::{self, Syntax, REG16, REG8, SEG};
+use crate::expr::Expr;
+use crate::flags::{AF, CF, DF, IF, OF, PF, SF, TF, ZF};
+
+const HELP: &str = "\
+s [N]             step N instructions (default 1), into calls and interrupts
+n                 step over a call, interrupt or repeated string instruction
+c [N]             continue until a breakpoint or stop, or for N instructions
+b ADDR [after N] [if COND]
+                  set a breakpoint, passing over its first N hits and any hit
+                  where COND is false
+t ADDR \"TEXT\" [if COND]
+                  set a tracepoint, which shows TEXT without stopping; {EXPR}
+                  in TEXT shows the value of EXPR in hex, {EXPR:d} in decimal
+bl                list breakpoints and tracepoints with their hits
+bd N|*            delete breakpoint or tracepoint N, or all of them
+w ADDR [LEN] [rwx]
+                  stop after reading, writing or executing LEN bytes (default 2)
+                  at ADDR; the default is w
+wt ADDR [LEN] [rwx]
+                  show those accesses without stopping
+wl                list watchpoints
+wd N|*            delete watchpoint N, or all of them
+r [REG [VALUE]]   show the registers, or show or set one (al..bh, ax..di, es..ds, ip, fl)
+f [FLAG=0|1 ...]  show the flags, or set them (cf pf af zf sf tf if df of)
+x [ADDR] [LEN]    hex dump LEN bytes (default 128) from ADDR (default DS:0000, then onward)
+e ADDR ITEM...    write bytes, or \"text\", to memory
+u [ADDR] [N]      disassemble N instructions (default 10) from ADDR (default CS:IP, then onward)
+k [N]             show N words (default 8) of the stack from SS:SP
+q                 quit
+
+ADDR is SEG:OFF or an offset into CS (DS for w, wt, x and e); either part may be a
+register. Addresses, values and bytes are hexadecimal and counts are decimal.
+COND and EXPR use C's operators on registers, flags (zf, cf, ...) and memory
+([bp-2] is a word, byte [si] a byte), as in cx == 1 && [bp-2] > 0x100; their
+numbers are decimal unless written 0x1F or 1Fh.
+An empty line repeats s, n, x or u.
+";
+
+// name, flag, DEBUG's abbreviation when set and clear
+const FLAGS: [(&str, u16, &str, &str); 9] = [
+    ("of", OF, "OV", "NV"), ("df", DF, "DN", "UP"), ("if", IF, "EI", "DI"),
+    ("sf", SF, "NG", "PL"), ("zf", ZF, "ZR", "NZ"), ("af", AF, "AC", "NA"),
+    ("pf", PF, "PE", "PO"), ("cf", CF, "CY", "NC"), ("tf", TF, "TR", ""),
+];
+
+/// The registers in the two-line format of DOS DEBUG, with a trailing newline.
+pub fn registers<B: Bus>(cpu: &X86Cpu<B>) -> String {
+    let r = &cpu.regs;
+    format!("AX={:04X}  BX={:04X}  CX={:04X}  DX={:04X}  SP={:04X}  BP={:04X}  SI={:04X}  DI={:04X}\n\
+             DS={:04X}  ES={:04X}  SS={:04X}  CS={:04X}  IP={:04X}   {}\n",
+            r.ax, r.bx, r.cx, r.dx, r.sp, r.bp, r.si, r.di,
+            r.ds, r.es, r.ss, r.cs, r.ip, flag_names(cpu))
+}
+
+fn flag_names<B: Bus>(cpu: &X86Cpu<B>) -> String {
+    let names: Vec<_> = FLAGS[..8].iter().map(|&(_, f, on, off)| if cpu.flag(f) { on } else { off }).collect();
+    names.join(" ")
+}
+
+/// `len` bytes of memory from physical address `addr`, 16 to a line with
+/// their ASCII text.
+pub fn hex_dump<B: Bus>(cpu: &mut X86Cpu<B>, addr: u32, len: u32) -> String {
+    let mut text = String::new();
+    for line in (0..len).step_by(16) {
+        let bytes: Vec<u8> = (line..len.min(line + 16)).map(|i| cpu.bus.read_u8((addr + i) & 0xFFFFF)).collect();
+        let hex: Vec<_> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let chars: String = bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+        text += &format!("{:05X}  {:<47}  {}\n", (addr + line) & 0xFFFFF, hex.join(" "), chars);
+    }
+    text
+}
+
+/// A listing line for the instruction at `cs:ip` (address, bytes and
+/// disassembly), and the instruction's length.
+pub fn listing<B: Bus>(cpu: &mut X86Cpu<B>, cs: u16, ip: u16, syntax: Syntax) -> (String, u16) {
+    let insn = cpu.decode_at(cs, ip);
+    let bytes: Vec<u8> = (0..insn.len).map(|i| cpu.bus.read_u8(physical(cs, ip.wrapping_add(i)))).collect();
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    let text = format!("{:04X}:{:04X}  {:<20} {}", cs, ip, hex.join(" "), disasm::format(&insn, &bytes, syntax));
+    (text, insn.len)
+}
+
+enum Error {
+    Io(io::Error),
+    Usage(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::Usage(msg)
+    }
+}
+
+// a breakpoint or tracepoint as it was set, and how often it has been hit
+struct Breakpoint {
+    seg: u16,
+    off: u16,
+    condition: Option<(String, Expr)>,
+    after: u64,
+    hits: u64,
+    // a tracepoint's message, shown instead of stopping
+    message: Option<(String, Vec<Piece>)>,
+}
+
+enum Piece {
+    Text(String),
+    Value(Expr, bool), // in decimal?
+}
+
+// a watchpoint as it was set, one CPU watchpoint per access
+struct Watch {
+    seg: u16,
+    off: u16,
+    len: u32, // up to the end of the segment, so a whole one is 10000h
+    accesses: Vec<Access>,
+    stop: bool,
+}
+
+impl Watch {
+    fn watchpoints(&self) -> impl Iterator<Item = Watchpoint> + '_ {
+        let start = physical(self.seg, self.off);
+        self.accesses.iter().map(move |&access| Watchpoint { range: start..start + self.len, access, stop: self.stop })
+    }
+
+    fn covers(&self, hit: &WatchHit) -> bool {
+        self.watchpoints().any(|w| w.access == hit.access && w.range.start < hit.addr + hit.len as u32
+            && hit.addr < w.range.end)
+    }
+}
+
+/// Debugger state that lasts between commands: breakpoints, watchpoints and
+/// where the last dump and listing stopped.
+#[derive(Default)]
+pub struct Debugger {
+    pub syntax: Syntax,
+    breakpoints: BTreeMap<u32, Breakpoint>,
+    stopped_by: Option<u32>,
+    watches: BTreeMap<u32, Watch>,
+    next_id: u32,
+    last: String,
+    dump_at: Option<(u16, u16)>,
+    list_at: Option<(u16, u16)>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Read commands from `input` until it ends or `q`, showing a prompt and
+    /// the results on `out`.
+    pub fn repl<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{}", registers(cpu))?;
+        writeln!(out, "{}", listing(cpu, cpu.regs.cs, cpu.regs.ip, self.syntax).0)?;
+        write!(out, "- ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(cpu, &line?, &mut out)? {
+                break;
+            }
+            write!(out, "- ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Run one command, writing its output (or what was wrong with it) to
+    /// `out`. Returns false for `q`.
+    pub fn command<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let result = self.exec(cpu, line, out);
+        // looking at unmapped memory is not a fault in the guest
+        cpu.bus.take_fault();
+        match result {
+            Ok(go_on) => Ok(go_on),
+            Err(Error::Io(e)) => Err(e),
+            Err(Error::Usage(msg)) => {
+                writeln!(out, "error: {}", msg)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn exec<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, line: &str, out: &mut impl Write) -> Result<bool, Error> {
+        let line = if line.trim().is_empty() { self.last.clone() } else { line.trim().to_string() };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        self.last = match cmd {
+            "s" | "n" => line.clone(),
+            "x" | "u" => cmd.to_string(),
+            _ => String::new(),
+        };
+        match (cmd, args) {
+            ("s", _) => {
+                let n = count(args.first(), 1)?;
+                let stop = self.run(cpu, Budget::Instructions(n), |_| false, out)?;
+                self.report(cpu, stop, out)?;
+            }
+            ("n", []) => {
+                let (cs, ip, sp) = (cpu.regs.cs, cpu.regs.ip, cpu.regs.sp);
+                let insn = cpu.decode_at(cs, ip);
+                let string = matches!(insn.op, Op::Movs | Op::Cmps | Op::Stos | Op::Lods | Op::Scas);
+                let stop = if matches!(insn.op, Op::Call | Op::CallFar | Op::Int | Op::Into) || string && insn.rep.is_some() {
+                    // back at the next instruction in this frame, not a recursive one
+                    let next = ip.wrapping_add(insn.len);
+                    self.run(cpu, Budget::Unlimited, |cpu| (cpu.regs.cs, cpu.regs.ip) == (cs, next) && cpu.regs.sp >= sp, out)?
+                } else {
+                    self.run(cpu, Budget::Instructions(1), |_| false, out)?
+                };
+                self.report(cpu, stop, out)?;
+            }
+            ("c", _) => {
+                let budget = match args.first() {
+                    Some(_) => Budget::Instructions(count(args.first(), 0)?),
+                    None => Budget::Unlimited,
+                };
+                let stop = self.run(cpu, budget, |_| false, out)?;
+                self.report(cpu, stop, out)?;
+            }
+            ("b", [addr, rest @ ..]) => {
+                let (seg, off) = address(cpu, addr, cpu.regs.cs)?;
+                let mut rest = rest;
+                let mut bp = Breakpoint { seg, off, condition: None, after: 0, hits: 0, message: None };
+                if let ["after", n, more @ ..] = rest {
+                    bp.after = count(Some(n), 0)?;
+                    rest = more;
+                }
+                match rest {
+                    [] => {}
+                    ["if", cond @ ..] => bp.condition = Some(condition(&cond.join(" "))?),
+                    _ => return Err("usage: b ADDR [after N] [if COND]".to_string().into()),
+                }
+                self.add_breakpoint(cpu, bp, "breakpoint", out)?;
+            }
+            ("t", [addr, ..]) => {
+                let (seg, off) = address(cpu, addr, cpu.regs.cs)?;
+                let usage = || "usage: t ADDR \"TEXT\" [if COND]".to_string();
+                let rest = tail(&line, 2).strip_prefix('"').ok_or_else(usage)?;
+                let (text, rest) = rest.split_once('"').ok_or_else(usage)?;
+                let condition = match rest.trim() {
+                    "" => None,
+                    rest => Some(condition(rest.strip_prefix("if ").ok_or_else(usage)?.trim())?),
+                };
+                let message = Some((text.to_string(), template(text)?));
+                let bp = Breakpoint { seg, off, condition, after: 0, hits: 0, message };
+                self.add_breakpoint(cpu, bp, "tracepoint", out)?;
+            }
+            ("bl", []) => {
+                for (id, bp) in &self.breakpoints {
+                    let mut text = format!("{:<3} {}", id, listing(cpu, bp.seg, bp.off, self.syntax).0);
+                    if let Some((message, _)) = &bp.message {
+                        text += &format!("  trace \"{}\"", message);
+                    }
+                    if bp.after > 0 {
+                        text += &format!("  after {}", bp.after);
+                    }
+                    if let Some((cond, _)) = &bp.condition {
+                        text += &format!("  if {}", cond);
+                    }
+                    let plural = if bp.hits == 1 { "" } else { "s" };
+                    writeln!(out, "{}  ({} hit{})", text, bp.hits, plural)?;
+                }
+            }
+            ("bd", ["*"]) => {
+                for (_, bp) in std::mem::take(&mut self.breakpoints) {
+                    cpu.remove_breakpoint(physical(bp.seg, bp.off));
+                }
+            }
+            ("bd", [id]) => {
+                let id: u32 = id.parse().map_err(|_| format!("bad breakpoint number {}", id))?;
+                let bp = self.breakpoints.remove(&id).ok_or_else(|| format!("no breakpoint {}", id))?;
+                let addr = physical(bp.seg, bp.off);
+                // another breakpoint may name the same address another way
+                if !self.breakpoints.values().any(|b| physical(b.seg, b.off) == addr) {
+                    cpu.remove_breakpoint(addr);
+                }
+            }
+            ("w" | "wt", [addr, rest @ ..]) if rest.len() <= 2 => {
+                let (seg, off) = address(cpu, addr, cpu.regs.ds)?;
+                let mut watch = Watch { seg, off, len: 2, accesses: vec![Access::Write], stop: cmd == "w" };
+                for arg in rest {
+                    if arg.chars().all(|c| "rwx".contains(c)) {
+                        watch.accesses = [('r', Access::Read), ('w', Access::Write), ('x', Access::Execute)]
+                            .into_iter().filter(|&(c, _)| arg.contains(c)).map(|(_, a)| a).collect();
+                    } else {
+                        watch.len = count(Some(arg), 2)?.clamp(1, 0x10000 - off as u64) as u32;
+                    }
+                }
+                watch.watchpoints().for_each(|w| cpu.add_watchpoint(w));
+                self.next_id += 1;
+                writeln!(out, "watchpoint {} at {}", self.next_id, describe(&watch))?;
+                self.watches.insert(self.next_id, watch);
+            }
+            ("wl", []) => {
+                for (id, watch) in &self.watches {
+                    writeln!(out, "{:<3} {}", id, describe(watch))?;
+                }
+            }
+            ("wd", ["*"]) => {
+                for (_, watch) in std::mem::take(&mut self.watches) {
+                    watch.watchpoints().for_each(|w| cpu.remove_watchpoint(&w));
+                }
+            }
+            ("wd", [id]) => {
+                let id: u32 = id.parse().map_err(|_| format!("bad watchpoint number {}", id))?;
+                let watch = self.watches.remove(&id).ok_or_else(|| format!("no watchpoint {}", id))?;
+                watch.watchpoints().for_each(|w| cpu.remove_watchpoint(&w));
+            }
+            ("r", []) => {
+                write!(out, "{}", registers(cpu))?;
+                writeln!(out, "{}", listing(cpu, cpu.regs.cs, cpu.regs.ip, self.syntax).0)?;
+            }
+            ("r", [name]) if !name.contains('=') => {
+                let value = register(cpu, name).ok_or_else(|| format!("unknown register {}", name))?;
+                writeln!(out, "{} {:04X}", name.to_ascii_uppercase(), value)?;
+            }
+            ("r", _) => {
+                let joined = args.join(" ");
+                let (name, value) = joined.split_once(['=', ' ']).ok_or_else(|| "usage: r REG VALUE".to_string())?;
+                let value = number(cpu, value.trim().trim_start_matches('=').trim())?;
+                set_register(cpu, name.trim(), value)?;
+            }
+            ("f", []) => {
+                let tf = if cpu.flag(TF) { " TR" } else { "" };
+                writeln!(out, "{}{}  FL={:04X}", flag_names(cpu), tf, cpu.flags())?;
+            }
+            ("f", _) => {
+                for arg in args {
+                    let (name, on) = match arg.split_once('=') {
+                        Some((name, "1")) => (name, true),
+                        Some((name, "0")) => (name, false),
+                        _ => return Err(format!("expected FLAG=0 or FLAG=1, got {}", arg).into()),
+                    };
+                    let &(_, f, _, _) = FLAGS.iter().find(|fl| fl.0.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("unknown flag {}", name))?;
+                    cpu.set_flag(f, on);
+                }
+            }
+            ("x", _) if args.len() <= 2 => {
+                let (seg, off) = match args.first() {
+                    Some(addr) => address(cpu, addr, cpu.regs.ds)?,
+                    None => self.dump_at.unwrap_or((cpu.regs.ds, 0)),
+                };
+                let len = count(args.get(1), 128)?.min(0x10000) as u32;
+                write!(out, "{}", hex_dump(cpu, physical(seg, off), len))?;
+                self.dump_at = Some((seg, off.wrapping_add(len as u16)));
+            }
+            ("e", [addr, ..]) => {
+                let (seg, off) = address(cpu, addr, cpu.regs.ds)?;
+                let bytes = items(tail(&line, 2))?;
+                if bytes.is_empty() {
+                    return Err("nothing to write".to_string().into());
+                }
+                for (i, &b) in bytes.iter().enumerate() {
+                    // straight to the bus, so a watchpoint does not fire for the debugger
+                    cpu.load(physical(seg, off.wrapping_add(i as u16)), &[b]);
+                }
+            }
+            ("u", _) if args.len() <= 2 => {
+                let (cs, mut ip) = match args.first() {
+                    Some(addr) => address(cpu, addr, cpu.regs.cs)?,
+                    None => self.list_at.unwrap_or((cpu.regs.cs, cpu.regs.ip)),
+                };
+                for _ in 0..count(args.get(1), 10)? {
+                    let (text, len) = listing(cpu, cs, ip, self.syntax);
+                    writeln!(out, "{}", text)?;
+                    ip = ip.wrapping_add(len);
+                }
+                self.list_at = Some((cs, ip));
+            }
+            ("k", _) if args.len() <= 1 => {
+                let (ss, sp, bp) = (cpu.regs.ss, cpu.regs.sp, cpu.regs.bp);
+                for i in 0..count(args.first(), 8)? as u16 {
+                    let off = sp.wrapping_add(i * 2);
+                    let mark = match off {
+                        _ if off == sp && off == bp => "  <- sp, bp",
+                        _ if off == sp => "  <- sp",
+                        _ if off == bp => "  <- bp",
+                        _ => "",
+                    };
+                    writeln!(out, "{:04X}:{:04X}  {:04X}{}", ss, off, cpu.bus.read_u16(physical(ss, off)), mark)?;
+                }
+            }
+            ("q", []) => return Ok(false),
+            ("h" | "?", []) => write!(out, "{}", HELP)?,
+            _ => return Err(format!("bad command {:?}; h lists the commands", line).into()),
+        }
+        Ok(true)
+    }
+
+    fn add_breakpoint<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, bp: Breakpoint, kind: &str, out: &mut impl Write)
+        -> io::Result<()> {
+        self.next_id += 1;
+        writeln!(out, "{} {} at {:04X}:{:04X}", kind, self.next_id, bp.seg, bp.off)?;
+        cpu.add_breakpoint(physical(bp.seg, bp.off));
+        self.breakpoints.insert(self.next_id, bp);
+        Ok(())
+    }
+
+    // run as X86Cpu::run_until does, but go on past breakpoints whose
+    // condition is false or whose first hits are passed over, and past
+    // tracepoints once their message is shown
+    fn run<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, mut budget: Budget, mut until: impl FnMut(&mut X86Cpu<B>) -> bool,
+                   out: &mut impl Write) -> io::Result<StopReason> {
+        loop {
+            let outcome = cpu.run_until(budget, &mut until);
+            match outcome.stop {
+                StopReason::Breakpoint => {}
+                // stepping onto a breakpoint hits it as running onto it does;
+                // resuming from there passes it, so it is not hit twice
+                StopReason::BudgetExhausted | StopReason::Condition if !cpu.mid_rep() => {
+                    self.hit(cpu, out)?;
+                    return Ok(if self.stopped_by.is_some() { StopReason::Breakpoint } else { outcome.stop });
+                }
+                stop => return Ok(stop),
+            }
+            // or an INT3 in the guest
+            if !self.hit(cpu, out)? || self.stopped_by.is_some() {
+                return Ok(StopReason::Breakpoint);
+            }
+            budget = match budget {
+                Budget::Unlimited => Budget::Unlimited,
+                Budget::Instructions(n) => Budget::Instructions(n.saturating_sub(outcome.retired)),
+                Budget::Cycles(n) => Budget::Cycles(n.saturating_sub(outcome.cycles)),
+            };
+        }
+    }
+
+    // count hits on the breakpoints at CS:IP and show tracepoint messages,
+    // noting the breakpoint to stop at; false if there are none here
+    fn hit<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, out: &mut impl Write) -> io::Result<bool> {
+        let at = physical(cpu.regs.cs, cpu.regs.ip);
+        let mut here = false;
+        for (&id, bp) in self.breakpoints.iter_mut().filter(|(_, b)| physical(b.seg, b.off) == at) {
+            here = true;
+            let hit = match &bp.condition {
+                None => true,
+                Some((_, cond)) => match cond.eval(cpu) {
+                    Ok(value) => value != 0,
+                    Err(e) => {
+                        writeln!(out, "breakpoint {}: {}", id, e)?;
+                        true
+                    }
+                },
+            };
+            if !hit {
+                continue;
+            }
+            bp.hits += 1;
+            match &bp.message {
+                Some((_, pieces)) => writeln!(out, "{}", message(pieces, cpu))?,
+                None if bp.hits > bp.after => {
+                    self.stopped_by.get_or_insert(id);
+                }
+                None => {}
+            }
+        }
+        Ok(here)
+    }
+
+    // accesses seen on the way, why the CPU stopped if it was not simply
+    // done, and where it is now
+    fn report<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, stop: StopReason, out: &mut impl Write) -> io::Result<()> {
+        for hit in cpu.take_watch_hits() {
+            match self.watches.iter().find(|(_, w)| !w.stop && w.covers(&hit)) {
+                Some((id, _)) => writeln!(out, "watchpoint {}: {}", id, hit)?,
+                None => writeln!(out, "watchpoint: {}", hit)?,
+            }
+        }
+        match stop {
+            StopReason::Watchpoint(hit) => match self.watches.iter().find(|(_, w)| w.stop && w.covers(&hit)) {
+                Some((id, _)) => writeln!(out, "watchpoint {}: {}", id, hit)?,
+                None => writeln!(out, "{}", stop)?,
+            },
+            StopReason::BudgetExhausted | StopReason::Condition => {}
+            StopReason::Breakpoint => match self.stopped_by.take() {
+                Some(id) => writeln!(out, "breakpoint {}", id)?,
+                None => writeln!(out, "{}", stop)?,
+            },
+            _ => writeln!(out, "stopped: {}", stop)?,
+        }
+        self.list_at = None;
+        writeln!(out, "{}", listing(cpu, cpu.regs.cs, cpu.regs.ip, self.syntax).0)
+    }
+}
+
+// where a watchpoint is and what it watches
+fn describe(watch: &Watch) -> String {
+    let accesses: String = watch.accesses.iter().map(|a| match a {
+        Access::Read => 'r',
+        Access::Write => 'w',
+        Access::Execute => 'x',
+    }).collect();
+    let trace = if watch.stop { "" } else { ", trace" };
+    let plural = if watch.len == 1 { "" } else { "s" };
+    format!("{:04X}:{:04X} ({} byte{}, {}{})", watch.seg, watch.off, watch.len, plural, accesses, trace)
+}
+
+fn condition(text: &str) -> Result<(String, Expr), String> {
+    let expr = Expr::parse(text).map_err(|e| format!("bad condition {}: {}", text, e))?;
+    Ok((text.to_string(), expr))
+}
+
+// a tracepoint message: text with {EXPR} or {EXPR:d} in it, and {{ and }}
+// for braces
+fn template(text: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find(['{', '}']) {
+        literal += &rest[..i];
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            literal += brace;
+            rest = after;
+            continue;
+        }
+        if brace == "}" {
+            return Err("unmatched } in message; write }} for a brace".into());
+        }
+        let (inner, after) = rest.split_once('}').ok_or("missing } in message")?;
+        let (expr, decimal) = match inner.rsplit_once(':') {
+            Some((expr, "d")) => (expr, true),
+            _ => (inner, false),
+        };
+        let expr = Expr::parse(expr).map_err(|e| format!("bad expression {}: {}", expr, e))?;
+        pieces.push(Piece::Text(std::mem::take(&mut literal)));
+        pieces.push(Piece::Value(expr, decimal));
+        rest = after;
+    }
+    literal += rest;
+    pieces.push(Piece::Text(literal));
+    Ok(pieces)
+}
+
+fn message<B: Bus>(pieces: &[Piece], cpu: &mut X86Cpu<B>) -> String {
+    pieces.iter().map(|piece| match piece {
+        Piece::Text(text) => text.clone(),
+        Piece::Value(expr, decimal) => match expr.eval(cpu) {
+            Ok(v) if *decimal => v.to_string(),
+            Ok(v) if v < 0 => format!("-{:X}", v.unsigned_abs()),
+            Ok(v) => format!("{:X}", v),
+            Err(e) => format!("<{}>", e),
+        },
+    }).collect()
+}
+
+// what follows the first `n` words of a command line
+fn tail(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        rest = rest.split_once(char::is_whitespace).map_or("", |(_, r)| r).trim_start();
+    }
+    rest
+}
+
+fn count(arg: Option<&&str>, default: u64) -> Result<u64, String> {
+    arg.map_or(Ok(default), |s| s.parse().map_err(|_| format!("bad count {}", s)))
+}
+
+fn register<B: Bus>(cpu: &X86Cpu<B>, name: &str) -> Option<u16> {
+    let name = name.to_ascii_lowercase();
+    let find = |names: &[&str]| names.iter().position(|&r| r == name).map(|i| i as u8);
+    let r = &cpu.regs;
+    match name.as_str() {
+        "ip" => Some(r.ip),
+        "fl" => Some(cpu.flags()),
+        _ => find(&REG16).map(|i| r.get16(i))
+            .or_else(|| find(&REG8).map(|i| r.get8(i) as u16))
+            .or_else(|| find(&SEG).map(|i| r.get_seg(i))),
+    }
+}
+
+fn set_register<B: Bus>(cpu: &mut X86Cpu<B>, name: &str, value: u16) -> Result<(), String> {
+    let name = name.to_ascii_lowercase();
+    let find = |names: &[&str]| names.iter().position(|&r| r == name).map(|i| i as u8);
+    match (name.as_str(), find(&REG16), find(&REG8), find(&SEG)) {
+        ("ip", ..) => cpu.regs.ip = value,
+        ("fl", ..) => cpu.set_flags(value),
+        (_, Some(i), _, _) => cpu.regs.set16(i, value),
+        (_, _, Some(i), _) if value <= 0xFF => cpu.regs.set8(i, value as u8),
+        (_, _, Some(_), _) => return Err(format!("{:X} does not fit in {}", value, name)),
+        (_, _, _, Some(i)) => cpu.regs.set_seg(i, value),
+        _ => return Err(format!("unknown register {}", name)),
+    }
+    Ok(())
+}
+
+// a hex number or a register
+fn number<B: Bus>(cpu: &X86Cpu<B>, text: &str) -> Result<u16, String> {
+    if let Some(value) = register(cpu, text) {
+        return Ok(value);
+    }
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_suffix(['h', 'H'])).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad number {}", text))
+}
+
+// SEG:OFF, or OFF in `default_seg`
+fn address<B: Bus>(cpu: &X86Cpu<B>, text: &str, default_seg: u16) -> Result<(u16, u16), String> {
+    match text.split_once(':') {
+        Some((seg, off)) => Ok((number(cpu, seg)?, number(cpu, off)?)),
+        None => Ok((default_seg, number(cpu, text)?)),
+    }
+}
+
+// hex bytes and quoted strings
+fn items(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '"' || c == '\'' {
+            let end = rest[1..].find(c).ok_or("unterminated string")?;
+            bytes.extend_from_slice(&rest.as_bytes()[1..1 + end]);
+            rest = &rest[end + 2..];
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let byte = u8::from_str_radix(&rest[..end], 16).map_err(|_| format!("bad byte {}", &rest[..end]))?;
+            bytes.push(byte);
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // dec cx is at 0003 and runs with CX = 3, 2 and 1
+    fn countdown() -> X86Cpu {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &assemble("mov cx, 3\nl: dec cx\njnz l\nhlt").unwrap().bytes);
+        cpu
+    }
+
+    // the output of each command
+    fn commands(debugger: &mut Debugger, cpu: &mut X86Cpu, lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| {
+            let mut out = Vec::new();
+            debugger.command(cpu, line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn tracepoints_log_whether_stepped_or_run_onto() {
+        for lines in [&["s", "s", "s", "c"][..], &["c"], &["s", "c"], &["n", "n", "n", "c"]] {
+            let (mut debugger, mut cpu) = (Debugger::new(), countdown());
+            commands(&mut debugger, &mut cpu, &[r#"t 3 "cx={cx}""#]);
+            let out = commands(&mut debugger, &mut cpu, lines).concat();
+            let logged: Vec<&str> = out.lines().filter(|l| l.starts_with("cx=")).collect();
+            assert_eq!(logged, ["cx=3", "cx=2", "cx=1"], "after {:?}", lines);
+            assert!(commands(&mut debugger, &mut cpu, &["bl"])[0].ends_with("(3 hits)\n"));
+            assert!(cpu.halted);
+        }
+    }
+
+    #[test]
+    fn stepping_a_repeated_string_instruction_hits_it_once() {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &assemble("mov cx, 3\nmov di, 0x100\nrep stosb\nhlt").unwrap().bytes);
+        let mut debugger = Debugger::new();
+        commands(&mut debugger, &mut cpu, &[r#"t 6 "cx={cx}""#]);
+        let out = commands(&mut debugger, &mut cpu, &["s", "s", "s", "s", "s", "s", "c"]).concat();
+        assert_eq!(out.lines().filter(|l| l.starts_with("cx=")).collect::<Vec<_>>(), ["cx=3"]);
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn hit_counts_agree_whether_stepped_or_run() {
+        for lines in [&["c"][..], &["s", "c"], &["s", "s", "s"]] {
+            let (mut debugger, mut cpu) = (Debugger::new(), countdown());
+            commands(&mut debugger, &mut cpu, &["b 3 after 1"]);
+            let out = commands(&mut debugger, &mut cpu, lines);
+            assert!(out.last().unwrap().starts_with("breakpoint 1\n"), "after {:?}: {:?}", lines, out);
+            assert_eq!((cpu.regs.ip, cpu.regs.cx), (3, 2), "after {:?}", lines);
+        }
+    }
+
+    #[test]
+    fn conditions_pass_over_hits() {
+        let (mut debugger, mut cpu) = (Debugger::new(), countdown());
+        let out = commands(&mut debugger, &mut cpu, &["b 3 if cx == 1 && [bp+0x10] == 0", "c", "bl"]);
+        assert!(out[1].starts_with("breakpoint 1\n"));
+        assert_eq!(cpu.regs.cx, 1);
+        assert!(out[2].ends_with("if cx == 1 && [bp+0x10] == 0  (1 hit)\n"));
+    }
+
+    #[test]
+    fn watchpoints_stop_or_log() {
+        let program = assemble("mov word [0x100], 5\nmov ax, [0x100]\nhlt").unwrap().bytes;
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &program);
+        let mut debugger = Debugger::new();
+        let out = commands(&mut debugger, &mut cpu, &["w 100", "wt 100 1 r", "c", "c"]);
+        assert_eq!(out[0], "watchpoint 1 at 0000:0100 (2 bytes, w)\n");
+        assert_eq!(out[1], "watchpoint 2 at 0000:0100 (1 byte, r, trace)\n");
+        // stopped after the write, then shown the read on the way to HLT
+        assert!(out[2].starts_with("watchpoint 1: write of 2 at 0x00100 by 0000:0000: 0000 -> 0005\n0000:0006 "), "{:?}", out[2]);
+        assert!(out[3].starts_with("watchpoint 2: read of 2 at 0x00100 by 0000:0006: 0005\nstopped: halted\n"), "{:?}", out[3]);
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn a_watchpoint_can_cover_a_whole_segment() {
+        let program = assemble("mov byte [0xFFFF], 1\nhlt").unwrap().bytes;
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, &program);
+        cpu.regs.ds = 0x1000;
+        let mut debugger = Debugger::new();
+        let out = commands(&mut debugger, &mut cpu, &["w ds:0 65536", "c"]);
+        assert_eq!(out[0], "watchpoint 1 at 1000:0000 (65536 bytes, w)\n");
+        assert!(out[1].starts_with("watchpoint 1: "), "{:?}", out[1]);
+        assert!(!cpu.halted);
+    }
+}
@@ -0,0 +1,354 @@
+// Instruction decoder: turns a byte stream into an `Instruction` that
+// `X86Cpu::execute` can run
+
+use crate::modrm::{Address, Base, ModRm, Operand};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    // ALU group, in reg-field order
+    Add, Or, Adc, Sbb, And, Sub, Xor, Cmp,
+    // shift group, in reg-field order (/6 is undefined)
+    Rol, Ror, Rcl, Rcr, Shl, Shr, Sar,
+    Test, Not, Neg, Mul, Imul, Div, Idiv,
+    Inc, Dec, Push, Pop, Xchg, Mov, Lea, Lds, Les,
+    Cbw, Cwd, Lahf, Sahf, Pushf, Popf, Xlat,
+    Daa, Das, Aaa, Aas, Aam, Aad,
+    Jcc(u8), Jmp, JmpFar, Call, CallFar, Ret, RetFar,
+    Loop, Loopz, Loopnz, Jcxz,
+    Clc, Stc, Cmc, Cld, Std, Cli, Sti,
+    In, Out, Int, Int3, Into, Iret,
+    Movs, Cmps, Stos, Lods, Scas,
+    Nop, Hlt, Wait, Esc,
+    Invalid,
+}
+
+const ALU: [Op; 8] = [Op::Add, Op::Or, Op::Adc, Op::Sbb, Op::And, Op::Sub, Op::Xor, Op::Cmp];
+const SHIFT: [Op; 8] = [Op::Rol, Op::Ror, Op::Rcl, Op::Rcr, Op::Shl, Op::Shr, Op::Invalid, Op::Sar];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    pub fn mask(self) -> u16 {
+        match self { Width::Byte => 0xFF, Width::Word => 0xFFFF }
+    }
+
+    pub fn sign(self) -> u16 {
+        match self { Width::Byte => 0x80, Width::Word => 0x8000 }
+    }
+}
+
+// F3 is REP for MOVS/STOS/LODS and REPE for CMPS/SCAS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rep {
+    Repe,
+    Repne,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Instruction {
+    pub op: Op,
+    pub width: Width,
+    pub dst: Operand,
+    pub src: Operand,
+    pub rep: Option<Rep>,
+    pub opcode: u8,
+    pub len: u16,
+}
+
+struct Decoder<F: FnMut() -> u8> {
+    fetch: F,
+    ip: u16,
+    len: u16,
+}
+
+impl<F: FnMut() -> u8> Decoder<F> {
+    fn u8(&mut self) -> u8 {
+        self.len += 1;
+        (self.fetch)()
+    }
+
+    fn u16(&mut self) -> u16 {
+        let low = self.u8() as u16;
+        let high = self.u8() as u16;
+        (high << 8) | low
+    }
+
+    fn imm(&mut self, w: Width) -> u16 {
+        match w {
+            Width::Byte => self.u8() as u16,
+            Width::Word => self.u16(),
+        }
+    }
+
+    fn modrm(&mut self) -> ModRm {
+        let byte = self.u8();
+        ModRm::decode(byte, || self.u8())
+    }
+
+    // relative branch targets are resolved against the end of the instruction
+    fn rel8(&mut self) -> Operand {
+        let disp = self.u8() as i8 as u16;
+        Operand::Rel(self.ip.wrapping_add(self.len).wrapping_add(disp))
+    }
+
+    fn rel16(&mut self) -> Operand {
+        let disp = self.u16();
+        Operand::Rel(self.ip.wrapping_add(self.len).wrapping_add(disp))
+    }
+}
+
+// The 8086 takes any number of prefixes, but then a segment full of them
+// would be one endless instruction; give up at 15 bytes as later CPUs do.
+const MAX_LEN: u16 = 15;
+
+// `ip` is the address of the first byte; `fetch` yields consecutive bytes from there
+pub fn decode(ip: u16, fetch: impl FnMut() -> u8) -> Instruction {
+    let mut d = Decoder { fetch, ip, len: 0 };
+    let mut seg = None;
+    let mut rep = None;
+    let mut opcode = d.u8();
+    loop {
+        match opcode {
+            0x26 | 0x2E | 0x36 | 0x3E => seg = Some((opcode >> 3) & 3),
+            0xF2 => rep = Some(Rep::Repne),
+            0xF3 => rep = Some(Rep::Repe),
+            0xF0 => {} // LOCK has no effect on a single CPU
+            _ => break,
+        }
+        if d.len == MAX_LEN {
+            return Instruction { op: Op::Invalid, width: Width::Word, dst: Operand::None, src: Operand::None, rep, opcode, len: d.len };
+        }
+        opcode = d.u8();
+    }
+
+    // bit 0 selects byte or word operands for most of the opcode map
+    let w = match opcode {
+        0x00..=0x3F if opcode & 7 < 6 => bit_width(opcode),
+        0x80..=0x8B | 0xA0..=0xAF | 0xC6 | 0xC7 | 0xD0..=0xD3 | 0xE4..=0xE7 | 0xEC..=0xEF => bit_width(opcode),
+        0xF6 | 0xF7 | 0xFE | 0xFF => bit_width(opcode),
+        0x27 | 0x2F | 0x37 | 0x3F | 0xB0..=0xB7 | 0xD4 | 0xD5 | 0xD7 => Width::Byte,
+        _ => Width::Word,
+    };
+
+    let none = Operand::None;
+    let (op, dst, src) = match opcode {
+        // ALU r/m,reg / reg,r/m / accumulator,imm
+        0x00..=0x3F if opcode & 7 < 6 => {
+            let op = ALU[(opcode >> 3) as usize];
+            match opcode & 7 {
+                0 | 1 => { let m = d.modrm(); (op, m.rm, Operand::Reg(m.reg)) }
+                2 | 3 => { let m = d.modrm(); (op, Operand::Reg(m.reg), m.rm) }
+                _ => (op, Operand::Reg(0), Operand::Imm(d.imm(w))),
+            }
+        }
+        0x06 | 0x0E | 0x16 | 0x1E => (Op::Push, Operand::Seg(opcode >> 3), none),
+        0x07 | 0x17 | 0x1F => (Op::Pop, Operand::Seg(opcode >> 3), none),
+        0x27 => (Op::Daa, none, none),
+        0x2F => (Op::Das, none, none),
+        0x37 => (Op::Aaa, none, none),
+        0x3F => (Op::Aas, none, none),
+
+        0x40..=0x47 => (Op::Inc, Operand::Reg(opcode & 7), none),
+        0x48..=0x4F => (Op::Dec, Operand::Reg(opcode & 7), none),
+        0x50..=0x57 => (Op::Push, Operand::Reg(opcode & 7), none),
+        0x58..=0x5F => (Op::Pop, Operand::Reg(opcode & 7), none),
+        0x70..=0x7F => (Op::Jcc(opcode & 0x0F), d.rel8(), none),
+
+        // 0x82 is an alias of 0x80; 0x83 sign-extends its imm8
+        0x80..=0x83 => {
+            let m = d.modrm();
+            let imm = if opcode == 0x83 { d.u8() as i8 as u16 } else { d.imm(w) };
+            (ALU[m.reg as usize], m.rm, Operand::Imm(imm))
+        }
+        0x84 | 0x85 => { let m = d.modrm(); (Op::Test, m.rm, Operand::Reg(m.reg)) }
+        0x86 | 0x87 => { let m = d.modrm(); (Op::Xchg, m.rm, Operand::Reg(m.reg)) }
+        0x88 | 0x89 => { let m = d.modrm(); (Op::Mov, m.rm, Operand::Reg(m.reg)) }
+        0x8A | 0x8B => { let m = d.modrm(); (Op::Mov, Operand::Reg(m.reg), m.rm) }
+        0x8C | 0x8E => {
+            let m = d.modrm();
+            match (opcode, m.reg) {
+                (0x8C, 0..=3) => (Op::Mov, m.rm, Operand::Seg(m.reg)),
+                (0x8E, 0 | 2 | 3) => (Op::Mov, Operand::Seg(m.reg), m.rm),
+                _ => (Op::Invalid, none, none),
+            }
+        }
+        0x8D | 0xC4 | 0xC5 => {
+            let m = d.modrm();
+            let op = match opcode { 0x8D => Op::Lea, 0xC4 => Op::Les, _ => Op::Lds };
+            match m.rm {
+                Operand::Mem(_) => (op, Operand::Reg(m.reg), m.rm),
+                _ => (Op::Invalid, none, none),
+            }
+        }
+        0x8F => {
+            let m = d.modrm();
+            if m.reg == 0 { (Op::Pop, m.rm, none) } else { (Op::Invalid, none, none) }
+        }
+
+        0x90 => (Op::Nop, none, none),
+        0x91..=0x97 => (Op::Xchg, Operand::Reg(0), Operand::Reg(opcode & 7)),
+        0x98 => (Op::Cbw, none, none),
+        0x99 => (Op::Cwd, none, none),
+        0x9A => {
+            let off = d.u16();
+            (Op::CallFar, Operand::Far(d.u16(), off), none)
+        }
+        0x9B => (Op::Wait, none, none),
+        0x9C => (Op::Pushf, none, none),
+        0x9D => (Op::Popf, none, none),
+        0x9E => (Op::Sahf, none, none),
+        0x9F => (Op::Lahf, none, none),
+
+        0xA0 | 0xA1 => (Op::Mov, Operand::Reg(0), Operand::Mem(direct(d.u16()))),
+        0xA2 | 0xA3 => (Op::Mov, Operand::Mem(direct(d.u16())), Operand::Reg(0)),
+        0xA4 | 0xA5 => (Op::Movs, string_dst(), string_src()),
+        0xA6 | 0xA7 => (Op::Cmps, string_src(), string_dst()),
+        0xA8 | 0xA9 => (Op::Test, Operand::Reg(0), Operand::Imm(d.imm(w))),
+        0xAA | 0xAB => (Op::Stos, string_dst(), Operand::Reg(0)),
+        0xAC | 0xAD => (Op::Lods, Operand::Reg(0), string_src()),
+        0xAE | 0xAF => (Op::Scas, Operand::Reg(0), string_dst()),
+        0xB0..=0xBF => (Op::Mov, Operand::Reg(opcode & 7), Operand::Imm(d.imm(w))),
+
+        0xC2 => (Op::Ret, Operand::Imm(d.u16()), none),
+        0xC3 => (Op::Ret, none, none),
+        0xC6 | 0xC7 => {
+            let m = d.modrm();
+            if m.reg == 0 { (Op::Mov, m.rm, Operand::Imm(d.imm(w))) } else { (Op::Invalid, none, none) }
+        }
+        0xCA => (Op::RetFar, Operand::Imm(d.u16()), none),
+        0xCB => (Op::RetFar, none, none),
+        0xCC => (Op::Int3, none, none),
+        0xCD => (Op::Int, Operand::Imm(d.u8() as u16), none),
+        0xCE => (Op::Into, none, none),
+        0xCF => (Op::Iret, none, none),
+
+        0xD0..=0xD3 => {
+            let m = d.modrm();
+            let count = if opcode < 0xD2 { Operand::Imm(1) } else { Operand::Cl };
+            match SHIFT[m.reg as usize] {
+                Op::Invalid => (Op::Invalid, none, none),
+                op => (op, m.rm, count),
+            }
+        }
+        0xD4 => (Op::Aam, Operand::Imm(d.u8() as u16), none),
+        0xD5 => (Op::Aad, Operand::Imm(d.u8() as u16), none),
+        0xD7 => (Op::Xlat, none, Operand::Mem(Address { seg: None, base: Base::Bx, disp: 0 })),
+        0xD8..=0xDF => { let m = d.modrm(); (Op::Esc, m.rm, none) }
+
+        0xE0 => (Op::Loopnz, d.rel8(), none),
+        0xE1 => (Op::Loopz, d.rel8(), none),
+        0xE2 => (Op::Loop, d.rel8(), none),
+        0xE3 => (Op::Jcxz, d.rel8(), none),
+        0xE4 | 0xE5 => (Op::In, Operand::Reg(0), Operand::Imm(d.u8() as u16)),
+        0xE6 | 0xE7 => (Op::Out, Operand::Imm(d.u8() as u16), Operand::Reg(0)),
+        0xE8 => (Op::Call, d.rel16(), none),
+        0xE9 => (Op::Jmp, d.rel16(), none),
+        0xEA => {
+            let off = d.u16();
+            (Op::JmpFar, Operand::Far(d.u16(), off), none)
+        }
+        0xEB => (Op::Jmp, d.rel8(), none),
+        0xEC | 0xED => (Op::In, Operand::Reg(0), Operand::Dx),
+        0xEE | 0xEF => (Op::Out, Operand::Dx, Operand::Reg(0)),
+
+        0xF4 => (Op::Hlt, none, none),
+        0xF5 => (Op::Cmc, none, none),
+        0xF6 | 0xF7 => {
+            let m = d.modrm();
+            match m.reg {
+                0 => (Op::Test, m.rm, Operand::Imm(d.imm(w))),
+                2 => (Op::Not, m.rm, none),
+                3 => (Op::Neg, m.rm, none),
+                4 => (Op::Mul, m.rm, none),
+                5 => (Op::Imul, m.rm, none),
+                6 => (Op::Div, m.rm, none),
+                7 => (Op::Idiv, m.rm, none),
+                _ => (Op::Invalid, none, none),
+            }
+        }
+        0xF8 => (Op::Clc, none, none),
+        0xF9 => (Op::Stc, none, none),
+        0xFA => (Op::Cli, none, none),
+        0xFB => (Op::Sti, none, none),
+        0xFC => (Op::Cld, none, none),
+        0xFD => (Op::Std, none, none),
+        0xFE => {
+            let m = d.modrm();
+            match m.reg {
+                0 => (Op::Inc, m.rm, none),
+                1 => (Op::Dec, m.rm, none),
+                _ => (Op::Invalid, none, none),
+            }
+        }
+        0xFF => {
+            let m = d.modrm();
+            let far = matches!(m.rm, Operand::Mem(_));
+            match m.reg {
+                0 => (Op::Inc, m.rm, none),
+                1 => (Op::Dec, m.rm, none),
+                2 => (Op::Call, m.rm, none),
+                3 if far => (Op::CallFar, m.rm, none),
+                4 => (Op::Jmp, m.rm, none),
+                5 if far => (Op::JmpFar, m.rm, none),
+                6 => (Op::Push, m.rm, none),
+                _ => (Op::Invalid, none, none),
+            }
+        }
+
+        _ => (Op::Invalid, none, none),
+    };
+
+    // LEA only wants the offset, so an override there is harmless; the
+    // ES:DI string destination already names its segment and cannot be overridden
+    let [dst, src] = [dst, src].map(|o| match o {
+        Operand::Mem(addr) if addr.seg.is_none() && seg.is_some() => Operand::Mem(Address { seg, ..addr }),
+        _ => o,
+    });
+
+    Instruction { op, width: w, dst, src, rep, opcode, len: d.len }
+}
+
+fn bit_width(opcode: u8) -> Width {
+    if opcode & 1 == 0 { Width::Byte } else { Width::Word }
+}
+
+// DS:SI
+fn string_src() -> Operand {
+    Operand::Mem(Address { seg: None, base: Base::Si, disp: 0 })
+}
+
+// ES:DI
+fn string_dst() -> Operand {
+    Operand::Mem(Address { seg: Some(0), base: Base::Di, disp: 0 })
+}
+
+fn direct(disp: u16) -> Address {
+    Address { seg: None, base: Base::None, disp }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endless_prefixes_are_invalid() {
+        let insn = decode(0, || 0x26);
+        assert_eq!(insn.op, Op::Invalid);
+        assert_eq!(insn.len, MAX_LEN);
+    }
+
+    #[test]
+    fn prefixes_up_to_the_limit_decode() {
+        // fourteen prefixes and a one-byte NOP
+        let mut bytes = [0xF3; 15];
+        bytes[14] = 0x90;
+        let mut i = 0;
+        let insn = decode(0, || { i += 1; bytes[i - 1] });
+        assert_eq!(insn.op, Op::Nop);
+        assert_eq!(insn.len, 15);
+    }
+}
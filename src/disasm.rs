@@ -0,0 +1,299 @@
+// Disassembler over the same decoder `X86Cpu::step` executes from, so the
+// text always describes what the CPU will do with the bytes
+
+use crate::decode::{decode, Instruction, Op, Rep, Width};
+use crate::modrm::{Address, Base, Operand};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// Intel/MASM style: `mov word ptr es:[bx+0x4], 0x1`
+    #[default]
+    Intel,
+    /// `mov word [es:bx+0x4], 0x1`
+    Nasm,
+    /// AT&T/GNU style: `movw $0x1,%es:0x4(%bx)`
+    Att,
+}
+
+/// One disassembled instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub offset: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+pub(crate) const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+pub(crate) const REG16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+pub(crate) const SEG: [&str; 4] = ["es", "cs", "ss", "ds"];
+const CC: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g"];
+
+/// Disassemble `code` as if it were loaded at offset `origin`. Bytes at the
+/// end that do not make up a whole instruction come out as data.
+pub fn disassemble(code: &[u8], origin: u16, syntax: Syntax) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < code.len() {
+        let offset = origin.wrapping_add(pos as u16);
+        let mut i = pos;
+        let insn = decode(offset, || {
+            i += 1;
+            code.get(i - 1).copied().unwrap_or(0)
+        });
+        let end = (pos + insn.len as usize).min(code.len());
+        let bytes = code[pos..end].to_vec();
+        let text = if pos + insn.len as usize > code.len() { data(&bytes, syntax) } else { format(&insn, &bytes, syntax) };
+        lines.push(Line { offset, bytes, text });
+        pos = end;
+    }
+    lines
+}
+
+fn data(bytes: &[u8], syntax: Syntax) -> String {
+    let list: Vec<_> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+    let directive = if syntax == Syntax::Att { ".byte" } else { "db" };
+    format!("{} {}", directive, list.join(", "))
+}
+
+/// The text of one decoded instruction; `bytes` are its encoding, which
+/// undefined opcodes are shown as.
+pub fn format(insn: &Instruction, bytes: &[u8], syntax: Syntax) -> String {
+    let Instruction { op, width: w, dst, src, rep, .. } = *insn;
+    let att = syntax == Syntax::Att;
+    let mut mnemonic = match op {
+        Op::Jcc(cc) => format!("j{}", CC[cc as usize]),
+        Op::Invalid => return data(bytes, syntax),
+        _ => mnemonic(op, syntax).to_string(),
+    };
+
+    // string instructions name their operands by the mnemonic's size suffix;
+    // only a source segment override needs spelling out, as a prefix
+    if matches!(op, Op::Movs | Op::Cmps | Op::Stos | Op::Lods | Op::Scas | Op::Xlat) {
+        if op != Op::Xlat {
+            mnemonic.push(if w == Width::Byte { 'b' } else { 'w' });
+        }
+        let mut prefix = String::new();
+        if let Some(r) = rep {
+            prefix += match (op, r) {
+                (_, Rep::Repne) => "repne ",
+                (Op::Cmps | Op::Scas, Rep::Repe) => "repe ",
+                _ => "rep ",
+            };
+        }
+        if let Some(Operand::Mem(Address { seg: Some(s), base: Base::Si | Base::Bx, .. })) = [dst, src].into_iter().find(|o| matches!(o, Operand::Mem(a) if a.base != Base::Di)) {
+            prefix += SEG[s as usize];
+            prefix += " ";
+        }
+        return prefix + &mnemonic;
+    }
+
+    // the decoder drops LOCK, but the bytes still carry it
+    let lock = bytes.iter().take_while(|b| matches!(b, 0x26 | 0x2E | 0x36 | 0x3E | 0xF0 | 0xF2 | 0xF3)).any(|&b| b == 0xF0);
+    let prefix = match rep {
+        Some(Rep::Repe) => "repe ",
+        Some(Rep::Repne) => "repne ",
+        None => "",
+    };
+    let prefix = if lock { format!("lock {}", prefix) } else { prefix.to_string() };
+    let f = Fmt { syntax, w };
+    let mut operands: Vec<String> = match op {
+        Op::Aam | Op::Aad if dst == Operand::Imm(10) => vec![],
+        Op::Rol | Op::Ror | Op::Rcl | Op::Rcr | Op::Shl | Op::Shr | Op::Sar if att && src == Operand::Imm(1) => vec![f.operand(dst, op)],
+        // the coprocessor opcode is the low opcode bits and the ModR/M reg field
+        Op::Esc => {
+            let at = bytes.iter().position(|b| (0xD8..=0xDF).contains(b)).unwrap_or(0);
+            let reg = bytes.get(at + 1).map_or(0, |m| m >> 3 & 7);
+            vec![f.operand(Operand::Imm((insn.opcode as u16 & 7) << 3 | reg as u16), op), f.operand(dst, op)]
+        }
+        _ => [dst, src].into_iter().filter(|&o| o != Operand::None).map(|o| f.operand(o, op)).collect(),
+    };
+    // a memory operand with nothing else to give its size needs it spelled out
+    let sized = matches!(dst, Operand::Mem(_)) && !matches!(src, Operand::Reg(_) | Operand::Seg(_)) && op != Op::Esc;
+    if att {
+        if sized && !matches!(op, Op::Jmp | Op::Call | Op::JmpFar | Op::CallFar) {
+            mnemonic.push(if w == Width::Byte { 'b' } else { 'w' });
+        }
+        operands.reverse();
+    } else if sized {
+        if let Some(first) = operands.first_mut() {
+            let size = match (op, syntax) {
+                (Op::JmpFar | Op::CallFar, Syntax::Nasm) => "far",
+                (Op::JmpFar | Op::CallFar, _) => "dword ptr",
+                (_, Syntax::Nasm) if w == Width::Byte => "byte",
+                (_, Syntax::Nasm) => "word",
+                _ if w == Width::Byte => "byte ptr",
+                _ => "word ptr",
+            };
+            *first = format!("{} {}", size, first);
+        }
+    }
+
+    let sep = if att { "," } else { ", " };
+    if operands.is_empty() {
+        format!("{}{}", prefix, mnemonic)
+    } else {
+        format!("{}{} {}", prefix, mnemonic, operands.join(sep))
+    }
+}
+
+fn mnemonic(op: Op, syntax: Syntax) -> &'static str {
+    let att = syntax == Syntax::Att;
+    match op {
+        Op::Add => "add", Op::Or => "or", Op::Adc => "adc", Op::Sbb => "sbb",
+        Op::And => "and", Op::Sub => "sub", Op::Xor => "xor", Op::Cmp => "cmp",
+        Op::Rol => "rol", Op::Ror => "ror", Op::Rcl => "rcl", Op::Rcr => "rcr",
+        Op::Shl => "shl", Op::Shr => "shr", Op::Sar => "sar",
+        Op::Test => "test", Op::Not => "not", Op::Neg => "neg", Op::Mul => "mul",
+        Op::Imul => "imul", Op::Div => "div", Op::Idiv => "idiv",
+        Op::Inc => "inc", Op::Dec => "dec", Op::Push => "push", Op::Pop => "pop",
+        Op::Xchg => "xchg", Op::Mov => "mov", Op::Lea => "lea", Op::Lds => "lds", Op::Les => "les",
+        Op::Cbw => if att { "cbtw" } else { "cbw" },
+        Op::Cwd => if att { "cwtd" } else { "cwd" },
+        Op::Lahf => "lahf", Op::Sahf => "sahf", Op::Pushf => "pushf", Op::Popf => "popf",
+        Op::Xlat => if syntax == Syntax::Nasm { "xlatb" } else { "xlat" },
+        Op::Daa => "daa", Op::Das => "das", Op::Aaa => "aaa", Op::Aas => "aas",
+        Op::Aam => "aam", Op::Aad => "aad",
+        Op::Jmp => "jmp", Op::Call => "call",
+        Op::JmpFar => if att { "ljmp" } else { "jmp" },
+        Op::CallFar => if att { "lcall" } else { "call" },
+        Op::Ret => "ret",
+        Op::RetFar => if att { "lret" } else { "retf" },
+        Op::Loop => "loop", Op::Loopz => "loopz", Op::Loopnz => "loopnz", Op::Jcxz => "jcxz",
+        Op::Clc => "clc", Op::Stc => "stc", Op::Cmc => "cmc", Op::Cld => "cld",
+        Op::Std => "std", Op::Cli => "cli", Op::Sti => "sti",
+        Op::In => "in", Op::Out => "out", Op::Int => "int", Op::Int3 => "int3",
+        Op::Into => "into", Op::Iret => "iret",
+        Op::Movs => "movs", Op::Cmps => "cmps", Op::Stos => "stos", Op::Lods => "lods", Op::Scas => "scas",
+        Op::Nop => "nop", Op::Hlt => "hlt", Op::Wait => "wait", Op::Esc => "esc",
+        Op::Jcc(_) | Op::Invalid => unreachable!("{:?} has no fixed mnemonic", op),
+    }
+}
+
+struct Fmt {
+    syntax: Syntax,
+    w: Width,
+}
+
+impl Fmt {
+    fn att(&self) -> bool {
+        self.syntax == Syntax::Att
+    }
+
+    fn operand(&self, o: Operand, op: Op) -> String {
+        let att = self.att();
+        let indirect = att && matches!(op, Op::Jmp | Op::Call | Op::JmpFar | Op::CallFar) && !matches!(o, Operand::Rel(_) | Operand::Far(..));
+        let text = match o {
+            Operand::Reg(r) if self.w == Width::Byte => REG8[r as usize & 7].to_string(),
+            Operand::Reg(r) => REG16[r as usize & 7].to_string(),
+            Operand::Seg(s) => SEG[s as usize & 3].to_string(),
+            Operand::Cl => "cl".to_string(),
+            Operand::Dx if att => return "(%dx)".to_string(),
+            Operand::Dx => "dx".to_string(),
+            Operand::Mem(a) => return if indirect { format!("*{}", self.mem(a)) } else { self.mem(a) },
+            Operand::Imm(v) if att => return format!("$0x{:x}", v),
+            Operand::Imm(v) | Operand::Rel(v) => return format!("0x{:x}", v),
+            Operand::Far(seg, off) if att => return format!("$0x{:x},$0x{:x}", seg, off),
+            Operand::Far(seg, off) => return format!("0x{:x}:0x{:x}", seg, off),
+            Operand::None => String::new(),
+        };
+        match (att, indirect) {
+            (true, true) => format!("*%{}", text),
+            (true, false) => format!("%{}", text),
+            _ => text,
+        }
+    }
+
+    fn mem(&self, a: Address) -> String {
+        let regs: &[&str] = match a.base {
+            Base::BxSi => &["bx", "si"],
+            Base::BxDi => &["bx", "di"],
+            Base::BpSi => &["bp", "si"],
+            Base::BpDi => &["bp", "di"],
+            Base::Si => &["si"],
+            Base::Di => &["di"],
+            Base::Bp => &["bp"],
+            Base::Bx => &["bx"],
+            Base::None => &[],
+        };
+        // displacements from a register read best signed, a direct address unsigned
+        let disp = match (regs.is_empty(), a.disp as i16) {
+            (true, _) => format!("0x{:x}", a.disp),
+            (false, 0) => String::new(),
+            (false, d) if d < 0 => format!("-0x{:x}", -(d as i32)),
+            (false, d) => format!("0x{:x}", d),
+        };
+        let seg = a.seg.map(|s| SEG[s as usize]);
+        match self.syntax {
+            Syntax::Att => {
+                let seg = seg.map_or(String::new(), |s| format!("%{}:", s));
+                let regs: Vec<_> = regs.iter().map(|r| format!("%{}", r)).collect();
+                if regs.is_empty() {
+                    format!("{}{}", seg, disp)
+                } else {
+                    format!("{}{}({})", seg, disp, regs.join(","))
+                }
+            }
+            _ => {
+                let mut inner = regs.join("+");
+                if !disp.is_empty() {
+                    if !inner.is_empty() && !disp.starts_with('-') {
+                        inner.push('+');
+                    }
+                    inner += &disp;
+                }
+                match (self.syntax, seg) {
+                    (Syntax::Nasm, Some(s)) => format!("[{}:{}]", s, inner),
+                    (_, Some(s)) => format!("{}:[{}]", s, inner),
+                    (_, None) => format!("[{}]", inner),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each encoding in Intel, NASM and AT&T syntax
+    const CASES: &[(&[u8], &str, &str, &str)] = &[
+        // ModR/M forms
+        (&[0x8B, 0x46, 0xFE], "mov ax, [bp-0x2]", "mov ax, [bp-0x2]", "mov -0x2(%bp),%ax"),
+        (&[0x88, 0x87, 0x34, 0x12], "mov [bx+0x1234], al", "mov [bx+0x1234], al", "mov %al,0x1234(%bx)"),
+        (&[0x03, 0x00], "add ax, [bx+si]", "add ax, [bx+si]", "add (%bx,%si),%ax"),
+        (&[0x8B, 0x0E, 0x00, 0x01], "mov cx, [0x100]", "mov cx, [0x100]", "mov 0x100,%cx"),
+        (&[0x31, 0xDB], "xor bx, bx", "xor bx, bx", "xor %bx,%bx"),
+        (&[0xFF, 0x17], "call word ptr [bx]", "call word [bx]", "call *(%bx)"),
+        (&[0xD1, 0xE0], "shl ax, 0x1", "shl ax, 0x1", "shl %ax"),
+        // segment overrides
+        (&[0x26, 0xC7, 0x47, 0x04, 0x01, 0x00], "mov word ptr es:[bx+0x4], 0x1", "mov word [es:bx+0x4], 0x1", "movw $0x1,%es:0x4(%bx)"),
+        (&[0x2E, 0x8A, 0x04], "mov al, cs:[si]", "mov al, [cs:si]", "mov %cs:(%si),%al"),
+        (&[0x2E, 0xAC], "cs lodsb", "cs lodsb", "cs lodsb"),
+        // prefixes
+        (&[0xF3, 0xA4], "rep movsb", "rep movsb", "rep movsb"),
+        (&[0xF3, 0xA6], "repe cmpsb", "repe cmpsb", "repe cmpsb"),
+        (&[0xF2, 0xAF], "repne scasw", "repne scasw", "repne scasw"),
+        (&[0xF0, 0xFF, 0x06, 0x00, 0x01], "lock inc word ptr [0x100]", "lock inc word [0x100]", "lock incw 0x100"),
+        // undefined opcodes show their bytes
+        (&[0x8D, 0xC0], "db 0x8d, 0xc0", "db 0x8d, 0xc0", ".byte 0x8d, 0xc0"),
+    ];
+
+    #[test]
+    fn syntaxes() {
+        for &(bytes, intel, nasm, att) in CASES {
+            for (syntax, text) in [(Syntax::Intel, intel), (Syntax::Nasm, nasm), (Syntax::Att, att)] {
+                let lines = disassemble(bytes, 0, syntax);
+                assert_eq!(lines.len(), 1, "{:02x?}", bytes);
+                assert_eq!((lines[0].text.as_str(), &lines[0].bytes[..]), (text, bytes), "{:?}", syntax);
+            }
+        }
+    }
+
+    #[test]
+    fn relative_targets_and_truncated_code() {
+        let lines = disassemble(&[0xEB, 0xFE, 0x74, 0x02, 0xB8, 0x01], 0x100, Syntax::Intel);
+        let text: Vec<_> = lines.iter().map(|l| (l.offset, l.text.as_str())).collect();
+        assert_eq!(text, [(0x100, "jmp 0x100"), (0x102, "je 0x106"), (0x104, "db 0xb8, 0x01")]);
+        assert_eq!(disassemble(&[0xB8], 0, Syntax::Att)[0].text, ".byte 0xb8");
+    }
+}
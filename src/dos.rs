@@ -0,0 +1,415 @@
+// DOS program loading: .COM and MZ .EXE images behind a Program Segment
+// Prefix, and the handful of INT 20h/21h services small programs use to
+// print and exit
+
+use std::fmt;
+use std::io::Write;
+
+use crate::bus::{physical, Bus};
+use crate::cpu::X86Cpu;
+use crate::flags::CF;
+
+/// Segment of the first paragraph past conventional memory (640 KiB).
+pub const MEMORY_TOP: u16 = 0xA000;
+
+// a .COM file shares its 64 KiB segment with the PSP and a word of stack
+const COM_MAX: usize = 0x10000 - 0x100 - 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    TooLarge { size: usize, max: usize },
+    /// The command tail must fit in the 127 bytes at PSP:0080.
+    TailTooLong,
+    /// The program and its minimum allocation do not fit below the memory top.
+    NoMemory,
+    /// An .EXE image that does not start with "MZ".
+    NotMz,
+    /// A header field that points outside the file or contradicts another.
+    BadHeader(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooLarge { size, max } => write!(f, "image is {} bytes, at most {} fit", size, max),
+            LoadError::TailTooLong => write!(f, "command tail is longer than 126 characters"),
+            LoadError::NoMemory => write!(f, "not enough memory below segment {:04X}", MEMORY_TOP),
+            LoadError::NotMz => write!(f, "not an MZ executable"),
+            LoadError::BadHeader(why) => write!(f, "malformed MZ header: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The 256-byte Program Segment Prefix DOS puts in front of a program.
+/// `args` is the command line after the program name; `memory_top` is the
+/// segment just past the program's memory, stored at offset 2.
+pub fn psp(args: &str, memory_top: u16) -> Result<[u8; 0x100], LoadError> {
+    let tail = if args.is_empty() { String::new() } else { format!(" {}", args) };
+    if tail.len() > 126 {
+        return Err(LoadError::TailTooLong);
+    }
+    let mut psp = [0; 0x100];
+    psp[0x00..0x02].copy_from_slice(&[0xCD, 0x20]); // INT 20h, for programs that exit by jumping to offset 0
+    psp[0x02..0x04].copy_from_slice(&memory_top.to_le_bytes());
+    psp[0x50..0x53].copy_from_slice(&[0xCD, 0x21, 0xCB]); // INT 21h; RETF
+    // two unopened FCBs with blank file names
+    psp[0x5D..0x68].fill(b' ');
+    psp[0x6D..0x78].fill(b' ');
+    // command tail: length, text, CR; this is also the default DTA
+    psp[0x80] = tail.len() as u8;
+    psp[0x81..0x81 + tail.len()].copy_from_slice(tail.as_bytes());
+    psp[0x81 + tail.len()] = b'\r';
+    Ok(psp)
+}
+
+/// Load a .COM program at `seg:0100` behind a PSP at `seg:0000`, the way
+/// DOS starts one: every segment register is `seg`, IP is 0100h and SP is
+/// at the top of the segment with a zero word pushed, so a RET reaches the
+/// INT 20h at offset 0.
+pub fn load_com<B: Bus>(cpu: &mut X86Cpu<B>, image: &[u8], seg: u16, args: &str) -> Result<(), LoadError> {
+    if image.len() > COM_MAX {
+        return Err(LoadError::TooLarge { size: image.len(), max: COM_MAX });
+    }
+    if seg as u32 + 0x1000 > MEMORY_TOP as u32 {
+        return Err(LoadError::NoMemory);
+    }
+    cpu.load(physical(seg, 0), &psp(args, MEMORY_TOP)?);
+    cpu.load(physical(seg, 0x100), image);
+    cpu.load(physical(seg, 0xFFFE), &[0, 0]);
+
+    let r = &mut cpu.regs;
+    (r.cs, r.ds, r.es, r.ss) = (seg, seg, seg, seg);
+    (r.ip, r.sp) = (0x100, 0xFFFE);
+    (r.ax, r.bx, r.cx, r.dx, r.bp) = (0, 0, 0x00FF, seg, 0);
+    (r.si, r.di) = (r.ip, r.sp);
+    Ok(())
+}
+
+/// The fields of an MZ header the loader uses. Sizes are in 16-byte
+/// paragraphs unless noted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MzHeader {
+    pub last_page_bytes: u16, // bytes used in the last 512-byte page, 0 if all of it
+    pub pages: u16,
+    pub relocations: u16,
+    pub header_paragraphs: u16,
+    pub min_alloc: u16,
+    pub max_alloc: u16,
+    pub ss: u16, // relative to the load segment
+    pub sp: u16,
+    pub ip: u16,
+    pub cs: u16, // relative to the load segment
+    pub reloc_offset: u16,
+}
+
+impl MzHeader {
+    pub fn parse(image: &[u8]) -> Result<MzHeader, LoadError> {
+        if image.len() < 2 || (&image[..2] != b"MZ" && &image[..2] != b"ZM") {
+            return Err(LoadError::NotMz);
+        }
+        if image.len() < 0x1C {
+            return Err(LoadError::BadHeader("file is shorter than the header"));
+        }
+        let word = |off: usize| u16::from_le_bytes([image[off], image[off + 1]]);
+        let h = MzHeader {
+            last_page_bytes: word(0x02),
+            pages: word(0x04),
+            relocations: word(0x06),
+            header_paragraphs: word(0x08),
+            min_alloc: word(0x0A),
+            max_alloc: word(0x0C),
+            ss: word(0x0E),
+            sp: word(0x10),
+            ip: word(0x14),
+            cs: word(0x16),
+            reloc_offset: word(0x18),
+        };
+        if h.last_page_bytes > 511 {
+            return Err(LoadError::BadHeader("last page byte count over 511"));
+        }
+        if h.pages == 0 || h.file_size() > image.len() {
+            return Err(LoadError::BadHeader("page count does not match the file size"));
+        }
+        if h.header_size() < 0x1C || h.header_size() > h.file_size() {
+            return Err(LoadError::BadHeader("header size out of range"));
+        }
+        let relocs_end = h.reloc_offset as usize + 4 * h.relocations as usize;
+        if h.relocations > 0 && (h.reloc_offset < 0x1C || relocs_end > h.header_size()) {
+            return Err(LoadError::BadHeader("relocation table outside the header"));
+        }
+        Ok(h)
+    }
+
+    fn file_size(&self) -> usize {
+        let full = self.pages as usize * 512;
+        if self.last_page_bytes == 0 { full } else { full - 512 + self.last_page_bytes as usize }
+    }
+
+    fn header_size(&self) -> usize {
+        self.header_paragraphs as usize * 16
+    }
+}
+
+/// Load an MZ executable behind a PSP at `psp_seg:0000`, the way DOS does:
+/// the load module goes at the next paragraph, its relocations are fixed up
+/// against that segment, and the program gets at least `min_alloc` and at
+/// most `max_alloc` paragraphs past its image before the memory top. CS:IP
+/// and SS:SP come from the header; DS and ES point at the PSP.
+pub fn load_exe<B: Bus>(cpu: &mut X86Cpu<B>, image: &[u8], psp_seg: u16, args: &str) -> Result<(), LoadError> {
+    let h = MzHeader::parse(image)?;
+    let module = &image[h.header_size()..h.file_size()];
+    let load_seg = psp_seg as u32 + 0x10;
+    let module_paragraphs = module.len().div_ceil(16) as u32;
+    let free = (MEMORY_TOP as u32).saturating_sub(load_seg + module_paragraphs);
+    if load_seg + module_paragraphs > MEMORY_TOP as u32 || free < h.min_alloc as u32 {
+        return Err(LoadError::NoMemory);
+    }
+    let memory_top = load_seg + module_paragraphs + free.min(h.max_alloc as u32);
+    let load_seg = load_seg as u16;
+
+    cpu.load(physical(psp_seg, 0), &psp(args, memory_top as u16)?);
+    cpu.load(physical(load_seg, 0), module);
+    for i in 0..h.relocations as usize {
+        let entry = &image[h.reloc_offset as usize + 4 * i..];
+        let off = u16::from_le_bytes([entry[0], entry[1]]);
+        let seg = u16::from_le_bytes([entry[2], entry[3]]).wrapping_add(load_seg);
+        let val = cpu.read_mem16(seg, off).wrapping_add(load_seg);
+        cpu.write_mem16(seg, off, val);
+    }
+
+    let r = &mut cpu.regs;
+    (r.cs, r.ip) = (h.cs.wrapping_add(load_seg), h.ip);
+    (r.ss, r.sp) = (h.ss.wrapping_add(load_seg), h.sp);
+    (r.ds, r.es) = (psp_seg, psp_seg);
+    (r.ax, r.bx, r.cx, r.dx, r.si, r.di, r.bp) = (0, 0, 0, 0, 0, 0, 0);
+    Ok(())
+}
+
+/// Serve the DOS calls tiny programs need from the host: INT 20h and
+/// INT 21h functions 00h and 4Ch halt the CPU (the exit code stays in AL),
+/// 02h and 09h write a character or a '$'-terminated string to `out`, and
+/// 30h reports DOS 5.0. Other INT 21h functions fail with CF set and AX=1.
+pub fn hook_services<B: Bus + 'static>(cpu: &mut X86Cpu<B>, mut out: impl Write + 'static) {
+    cpu.hook_interrupt(0x20, |cpu| {
+        cpu.halted = true;
+        true
+    });
+    cpu.hook_interrupt(0x21, move |cpu| {
+        let ok = match cpu.regs.get8(4) {
+            0x00 | 0x4C => { cpu.halted = true; true }
+            0x02 => {
+                cpu.regs.set8(0, cpu.regs.get8(2));
+                out.write_all(&[cpu.regs.get8(2)]).is_ok()
+            }
+            0x09 => {
+                let mut text = Vec::new();
+                let mut off = cpu.regs.dx;
+                loop {
+                    let c = cpu.read_mem8(cpu.regs.ds, off);
+                    if c == b'$' || text.len() == 0x10000 {
+                        break;
+                    }
+                    text.push(c);
+                    off = off.wrapping_add(1);
+                }
+                cpu.regs.set8(0, b'$');
+                out.write_all(&text).is_ok()
+            }
+            0x30 => { cpu.regs.ax = 0x0005; true }
+            _ => false,
+        };
+        let _ = out.flush();
+        if !ok {
+            cpu.regs.ax = 1;
+        }
+        cpu.set_flag(CF, !ok);
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::asm::assemble;
+    use crate::{Budget, StopReason};
+
+    // what the hooked services write, kept where the test can see it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn psp_layout() {
+        let psp = psp("a.txt /v", 0x9000).unwrap();
+        assert_eq!(psp[0x00..0x04], [0xCD, 0x20, 0x00, 0x90]);
+        assert_eq!(psp[0x50..0x53], [0xCD, 0x21, 0xCB]);
+        assert_eq!(psp[0x5D..0x68], *b"           ");
+        assert_eq!(psp[0x6D..0x78], *b"           ");
+        assert_eq!(psp[0x80], 9);
+        assert_eq!(psp[0x81..0x8B], *b" a.txt /v\r");
+
+        let psp = super::psp("", 0x9000).unwrap();
+        assert_eq!(psp[0x80..0x82], [0, b'\r']);
+    }
+
+    #[test]
+    fn command_tails_fit_in_126_bytes() {
+        let psp = psp(&"x".repeat(125), MEMORY_TOP).unwrap();
+        assert_eq!((psp[0x80], psp[0xFF]), (126, b'\r'));
+        assert_eq!(super::psp(&"x".repeat(126), MEMORY_TOP), Err(LoadError::TailTooLong));
+    }
+
+    #[test]
+    fn com_programs_start_like_dos_starts_them() {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0x1FFFE, &[0xAA, 0xBB]);
+        load_com(&mut cpu, &[0x90, 0xC3], 0x1000, "hi").unwrap();
+        let r = cpu.regs;
+        assert_eq!((r.cs, r.ds, r.es, r.ss), (0x1000, 0x1000, 0x1000, 0x1000));
+        assert_eq!((r.ip, r.sp), (0x100, 0xFFFE));
+        assert_eq!((r.ax, r.bx, r.cx, r.dx, r.si, r.di, r.bp), (0, 0, 0x00FF, 0x1000, 0x100, 0xFFFE, 0));
+        assert_eq!(cpu.read_mem16(0x1000, 0xFFFE), 0);
+        assert_eq!(cpu.read_mem16(0x1000, 0x100), 0xC390);
+        assert_eq!(cpu.read_mem16(0x1000, 0x02), MEMORY_TOP);
+        assert_eq!(cpu.read_mem8(0x1000, 0x80), 3);
+    }
+
+    #[test]
+    fn com_programs_must_fit() {
+        let mut cpu = X86Cpu::new();
+        let max = 0x10000 - 0x100 - 2;
+        assert_eq!(load_com(&mut cpu, &vec![0; max + 1], 0x1000, ""), Err(LoadError::TooLarge { size: max + 1, max }));
+        assert_eq!(load_com(&mut cpu, &[0x90], 0x9001, ""), Err(LoadError::NoMemory));
+        assert_eq!(load_com(&mut cpu, &[0x90], 0x1000, &"x".repeat(200)), Err(LoadError::TailTooLong));
+    }
+
+    #[test]
+    fn services_print_and_exit() {
+        let program = assemble("
+            org 100h
+            mov dx, msg
+            mov ah, 9
+            int 21h
+            mov dl, '!'
+            mov ah, 2
+            int 21h
+            mov ax, 4C07h
+            int 21h
+        msg:
+            db 'hello$'
+        ").unwrap();
+        let mut cpu = X86Cpu::new();
+        load_com(&mut cpu, &program.bytes, 0x1000, "").unwrap();
+        let out = Output::default();
+        hook_services(&mut cpu, out.clone());
+        assert_eq!(cpu.run(Budget::Instructions(100)).stop, StopReason::Halted);
+        assert_eq!(*out.0.borrow(), b"hello!");
+        assert_eq!(cpu.regs.get8(0), 7);
+        assert!(!cpu.flag(CF));
+    }
+
+    #[test]
+    fn unknown_services_fail_with_cf() {
+        let mut cpu = X86Cpu::new();
+        load_com(&mut cpu, &[0xB4, 0x99, 0xCD, 0x21, 0xF4], 0x1000, "").unwrap(); // mov ah, 99h; int 21h; hlt
+        hook_services(&mut cpu, Output::default());
+        cpu.run(Budget::Instructions(100));
+        assert_eq!(cpu.regs.ax, 1);
+        assert!(cpu.flag(CF));
+    }
+
+    // an MZ file with its relocation table at 1Ch and `module` after the
+    // header, loading CS:IP at 0000:0000 and SS:SP at 0010:0100
+    fn mz(module: &[u8], relocs: &[(u16, u16)], min_alloc: u16, max_alloc: u16) -> Vec<u8> {
+        let header = (0x1C + 4 * relocs.len()).next_multiple_of(16);
+        let size = header + module.len();
+        let mut image = vec![0; header];
+        for (off, val) in [
+            (0x02, size % 512), (0x04, size.div_ceil(512)), (0x06, relocs.len()), (0x08, header / 16),
+            (0x0A, min_alloc as usize), (0x0C, max_alloc as usize), (0x0E, 0x10), (0x10, 0x100), (0x18, 0x1C),
+        ] {
+            image[off..off + 2].copy_from_slice(&(val as u16).to_le_bytes());
+        }
+        image[..2].copy_from_slice(b"MZ");
+        for (i, &(off, seg)) in relocs.iter().enumerate() {
+            image[0x1C + 4 * i..0x20 + 4 * i].copy_from_slice(&[off.to_le_bytes(), seg.to_le_bytes()].concat());
+        }
+        image.extend_from_slice(module);
+        image
+    }
+
+    fn set_word(image: &mut [u8], off: usize, val: u16) {
+        image[off..off + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    #[test]
+    fn exe_relocations_are_fixed_up() {
+        let mut module = vec![0; 0x20];
+        module[0..3].copy_from_slice(&[0xB8, 0x01, 0x00]); // mov ax, seg 0001
+        module[0x10..0x12].copy_from_slice(&[0x34, 0x12]);
+        let mut cpu = X86Cpu::new();
+        load_exe(&mut cpu, &mz(&module, &[(1, 0), (0, 1)], 0, 0xFFFF), 0x1000, "").unwrap();
+        assert_eq!(cpu.read_mem16(0x1010, 1), 0x1011);
+        assert_eq!(cpu.read_mem16(0x1011, 0), 0x2244);
+        let r = cpu.regs;
+        assert_eq!((r.cs, r.ip, r.ss, r.sp), (0x1010, 0, 0x1020, 0x100));
+        assert_eq!((r.ds, r.es), (0x1000, 0x1000));
+    }
+
+    #[test]
+    fn exe_memory_is_allocated_between_min_and_max() {
+        let module = [0x90; 0x20];
+        let mut cpu = X86Cpu::new();
+        // the module takes two paragraphs past the PSP's 10h
+        load_exe(&mut cpu, &mz(&module, &[], 0x10, 0x100), 0x1000, "").unwrap();
+        assert_eq!(cpu.read_mem16(0x1000, 2), 0x1112);
+        load_exe(&mut cpu, &mz(&module, &[], 0x10, 0xFFFF), 0x1000, "").unwrap();
+        assert_eq!(cpu.read_mem16(0x1000, 2), MEMORY_TOP);
+        let free = MEMORY_TOP - 0x1012;
+        assert!(load_exe(&mut cpu, &mz(&module, &[], free, free), 0x1000, "").is_ok());
+        assert_eq!(load_exe(&mut cpu, &mz(&module, &[], free + 1, 0xFFFF), 0x1000, ""), Err(LoadError::NoMemory));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let image = mz(&[0x90; 0x20], &[(0, 0)], 0, 0xFFFF);
+        assert_eq!(MzHeader::parse(b"PE"), Err(LoadError::NotMz));
+        assert!(matches!(MzHeader::parse(&image[..0x10]), Err(LoadError::BadHeader(_))));
+
+        let mut bad = image.clone();
+        set_word(&mut bad, 0x02, 512);
+        assert_eq!(MzHeader::parse(&bad), Err(LoadError::BadHeader("last page byte count over 511")));
+
+        let mut bad = image.clone();
+        set_word(&mut bad, 0x06, 2); // the second entry runs past the header
+        assert_eq!(MzHeader::parse(&bad), Err(LoadError::BadHeader("relocation table outside the header")));
+        let mut bad = image.clone();
+        set_word(&mut bad, 0x18, 0x10);
+        assert_eq!(MzHeader::parse(&bad), Err(LoadError::BadHeader("relocation table outside the header")));
+
+        let mut bad = image.clone();
+        set_word(&mut bad, 0x08, 4); // 40h bytes of header in a 40h-byte file is fine, 50h is not
+        assert!(MzHeader::parse(&bad).is_ok());
+        set_word(&mut bad, 0x08, 5);
+        assert_eq!(MzHeader::parse(&bad), Err(LoadError::BadHeader("header size out of range")));
+
+        let mut bad = image;
+        set_word(&mut bad, 0x04, 2);
+        assert_eq!(MzHeader::parse(&bad), Err(LoadError::BadHeader("page count does not match the file size")));
+    }
+}
@@ -0,0 +1,342 @@
+// Expressions over the CPU's registers, flags and memory, for conditional
+// breakpoints and tracepoints: `cx == 1 && [bp-2] > 0x100`
+//
+// The operators are C's, with C's precedence, on signed 64-bit values;
+// comparisons and ! give 0 or 1. Registers and memory read as unsigned.
+
+use std::fmt;
+
+use crate::bus::{physical, Bus};
+use crate::cpu::X86Cpu;
+use crate::decode::Width;
+use crate::disasm::{REG16, REG8, SEG};
+use crate::flags::{AF, CF, DF, IF, OF, PF, SF, TF, ZF};
+
+const FLAGS: [(&str, u16); 9] = [
+    ("cf", CF), ("pf", PF), ("af", AF), ("zf", ZF), ("sf", SF),
+    ("tf", TF), ("if", IF), ("df", DF), ("of", OF),
+];
+
+// operators by precedence, loosest first
+const BINARY: [&[&str]; 10] = [
+    &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="],
+    &["<", "<=", ">", ">="], &["<<", ">>"], &["+", "-"], &["*", "/", "%"],
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+impl From<String> for ExprError {
+    fn from(message: String) -> ExprError {
+        ExprError { message }
+    }
+}
+
+impl From<&str> for ExprError {
+    fn from(message: &str) -> ExprError {
+        ExprError { message: message.to_string() }
+    }
+}
+
+/// A parsed expression, see [`Expr::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr(Node);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Num(i64),
+    Reg16(u8),
+    Reg8(u8),
+    Seg(u8),
+    Ip,
+    Flags,
+    Flag(u16),
+    // a segment of None means SS for an address using BP, else DS
+    Mem { width: Width, seg: Option<Box<Node>>, off: Box<Node> },
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+impl Expr {
+    /// Parse `text`. Operands are numbers (decimal, `0x1F` or `1Fh` hex),
+    /// registers (`ax`, `al`, `ds`, `ip`), `fl` for FLAGS, flags by name (`zf`
+    /// is 0 or 1) and memory: `[bp-2]` is a word, `byte [si]` a byte, and
+    /// `[es:di]` names the segment, which is otherwise SS for an address using
+    /// BP and DS for any other, as the CPU does.
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        let toks = tokenize(text)?;
+        let mut pos = 0;
+        let node = expr(&toks, &mut pos, 0)?;
+        match toks.get(pos) {
+            None => Ok(Expr(node)),
+            Some(tok) => Err(format!("unexpected {} in expression", tok).into()),
+        }
+    }
+
+    /// The expression's value for the current state of `cpu`. Memory is read
+    /// straight from the bus, so watchpoints do not see it and an access to
+    /// unmapped memory is not a guest fault.
+    pub fn eval<B: Bus>(&self, cpu: &mut X86Cpu<B>) -> Result<i64, ExprError> {
+        let value = eval(&self.0, cpu);
+        cpu.bus.take_fault();
+        value
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = ExprError;
+
+    fn from_str(text: &str) -> Result<Expr, ExprError> {
+        Expr::parse(text)
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<&str>, String> {
+    let mut toks = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len())
+        } else if ["==", "!=", "<=", ">=", "&&", "||", "<<", ">>"].iter().any(|op| rest.starts_with(op)) {
+            2
+        } else if "+-*/%&|^!~<>()[]:".contains(c) {
+            1
+        } else {
+            return Err(format!("unexpected character {:?}", c));
+        };
+        toks.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    Ok(toks)
+}
+
+// precedence climbing over the binary operators
+fn expr(t: &[&str], pos: &mut usize, min: usize) -> Result<Node, String> {
+    let mut lhs = unary(t, pos)?;
+    while let Some(&tok) = t.get(*pos) {
+        let Some(prec) = BINARY.iter().position(|ops| ops.contains(&tok)) else {
+            break;
+        };
+        if prec < min {
+            break;
+        }
+        let op = BINARY[prec].iter().find(|&&op| op == tok).unwrap();
+        *pos += 1;
+        let rhs = expr(t, pos, prec + 1)?;
+        lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+fn unary(t: &[&str], pos: &mut usize) -> Result<Node, String> {
+    let tok = t.get(*pos).ok_or("expression expected")?.to_ascii_lowercase();
+    *pos += 1;
+    let find = |names: &[&str]| names.iter().position(|&r| r == tok).map(|i| i as u8);
+    let node = match tok.as_str() {
+        "-" | "!" | "~" => {
+            let op = ["-", "!", "~"].into_iter().find(|&op| op == tok).unwrap();
+            Node::Unary(op, Box::new(unary(t, pos)?))
+        }
+        "+" => unary(t, pos)?,
+        "(" => {
+            let node = expr(t, pos, 0)?;
+            close(t, pos, ")")?;
+            node
+        }
+        "byte" | "word" | "[" => {
+            let width = if tok == "byte" { Width::Byte } else { Width::Word };
+            if tok != "[" {
+                close(t, pos, "[")?;
+            }
+            let mut off = expr(t, pos, 0)?;
+            let mut seg = None;
+            if t.get(*pos) == Some(&":") {
+                *pos += 1;
+                seg = Some(Box::new(off));
+                off = expr(t, pos, 0)?;
+            }
+            close(t, pos, "]")?;
+            Node::Mem { width, seg, off: Box::new(off) }
+        }
+        "ip" => Node::Ip,
+        "fl" => Node::Flags,
+        _ if tok.starts_with(|c: char| c.is_ascii_digit()) => {
+            Node::Num(number(&tok).ok_or_else(|| format!("bad number {}", tok))?)
+        }
+        _ => find(&REG16).map(Node::Reg16)
+            .or_else(|| find(&REG8).map(Node::Reg8))
+            .or_else(|| find(&SEG).map(Node::Seg))
+            .or_else(|| FLAGS.iter().find(|&&(name, _)| name == tok).map(|&(_, f)| Node::Flag(f)))
+            .ok_or_else(|| format!("unexpected {} in expression", tok))?,
+    };
+    Ok(node)
+}
+
+// decimal, or hex written 0x1F or 1Fh; `tok` is in lower case
+fn number(tok: &str) -> Option<i64> {
+    let (digits, radix) = match tok.strip_prefix("0x").or_else(|| tok.strip_suffix('h')) {
+        Some(hex) => (hex, 16),
+        None => (tok, 10),
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+fn close(t: &[&str], pos: &mut usize, tok: &str) -> Result<(), String> {
+    if t.get(*pos) != Some(&tok) {
+        return Err(format!("missing {}", tok));
+    }
+    *pos += 1;
+    Ok(())
+}
+
+// whether an address is BP-based, so in the stack segment
+fn uses_bp(node: &Node) -> bool {
+    match node {
+        Node::Reg16(5) => true,
+        Node::Unary(_, n) => uses_bp(n),
+        Node::Binary(_, a, b) => uses_bp(a) || uses_bp(b),
+        _ => false,
+    }
+}
+
+fn eval<B: Bus>(node: &Node, cpu: &mut X86Cpu<B>) -> Result<i64, ExprError> {
+    let r = &cpu.regs;
+    let v = match node {
+        Node::Num(n) => *n,
+        Node::Reg16(i) => r.get16(*i) as i64,
+        Node::Reg8(i) => r.get8(*i) as i64,
+        Node::Seg(i) => r.get_seg(*i) as i64,
+        Node::Ip => r.ip as i64,
+        Node::Flags => cpu.flags() as i64,
+        Node::Flag(f) => cpu.flag(*f) as i64,
+        Node::Mem { width, seg, off } => {
+            let seg = match seg {
+                Some(seg) => eval(seg, cpu)? as u16,
+                None if uses_bp(off) => cpu.regs.ss,
+                None => cpu.regs.ds,
+            };
+            let off = eval(off, cpu)? as u16;
+            match width {
+                Width::Byte => cpu.bus.read_u8(physical(seg, off)) as i64,
+                Width::Word => {
+                    let low = cpu.bus.read_u8(physical(seg, off)) as i64;
+                    low | (cpu.bus.read_u8(physical(seg, off.wrapping_add(1))) as i64) << 8
+                }
+            }
+        }
+        Node::Unary(op, n) => {
+            let n = eval(n, cpu)?;
+            match *op {
+                "-" => n.wrapping_neg(),
+                "!" => (n == 0) as i64,
+                _ => !n,
+            }
+        }
+        // short-circuit, so `bx != 0 && [bx] == 1` does not read [0]
+        Node::Binary("&&", a, b) => (eval(a, cpu)? != 0 && eval(b, cpu)? != 0) as i64,
+        Node::Binary("||", a, b) => (eval(a, cpu)? != 0 || eval(b, cpu)? != 0) as i64,
+        Node::Binary(op, a, b) => {
+            let (a, b) = (eval(a, cpu)?, eval(b, cpu)?);
+            match *op {
+                "|" => a | b,
+                "^" => a ^ b,
+                "&" => a & b,
+                "==" => (a == b) as i64,
+                "!=" => (a != b) as i64,
+                "<" => (a < b) as i64,
+                "<=" => (a <= b) as i64,
+                ">" => (a > b) as i64,
+                ">=" => (a >= b) as i64,
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                _ if b == 0 => return Err("division by zero".into()),
+                "/" => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            }
+        }
+    };
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(cpu: &mut X86Cpu, text: &str) -> Result<i64, ExprError> {
+        Expr::parse(text)?.eval(cpu)
+    }
+
+    #[test]
+    fn precedence() {
+        let mut cpu = X86Cpu::new();
+        (cpu.regs.ax, cpu.regs.cx) = (0x1234, 3);
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("7 - 2 - 1", 4),
+            ("1 << 2 + 1", 8),
+            ("2 | 1 == 1", 3),
+            ("1 < 2 == 1", 1),
+            ("-2 * -3", 6),
+            ("!0 + ~0", 0),
+            ("cx == 3 && ah == 0x12 || 0", 1),
+            ("ax & 0xFF", 0x34),
+            ("10 + 10h + 0x10", 42),
+        ];
+        for (text, value) in cases {
+            assert_eq!(eval(&mut cpu, text), Ok(value), "{}", text);
+        }
+    }
+
+    #[test]
+    fn numbers_are_decimal_or_hex() {
+        let mut cpu = X86Cpu::new();
+        assert_eq!(eval(&mut cpu, "0x1f + 1Fh"), Ok(62));
+        assert_eq!(eval(&mut cpu, "10b"), Err("bad number 10b".into()));
+        assert_eq!(eval(&mut cpu, "0b10"), Err("bad number 0b10".into()));
+    }
+
+    #[test]
+    fn memory_defaults_to_ss_for_bp() {
+        let mut cpu = X86Cpu::new();
+        (cpu.regs.ss, cpu.regs.ds, cpu.regs.bp) = (0x2000, 0x3000, 0x10);
+        cpu.load(physical(0x2000, 0x0E), &[0x34, 0x12]);
+        cpu.load(physical(0x3000, 0x0E), &[0x78, 0x56]);
+        assert_eq!(eval(&mut cpu, "[bp-2]"), Ok(0x1234));
+        assert_eq!(eval(&mut cpu, "byte [bp-2]"), Ok(0x34));
+        assert_eq!(eval(&mut cpu, "[bx+0xE]"), Ok(0x5678));
+        assert_eq!(eval(&mut cpu, "[ds:bp-2]"), Ok(0x5678));
+        assert_eq!(eval(&mut cpu, "word [0x2000:0xE]"), Ok(0x1234));
+    }
+
+    #[test]
+    fn logic_short_circuits() {
+        let mut cpu = X86Cpu::new();
+        assert_eq!(eval(&mut cpu, "0 && 1 / 0"), Ok(0));
+        assert_eq!(eval(&mut cpu, "1 || 1 / 0"), Ok(1));
+        assert_eq!(eval(&mut cpu, "1 && 1 / 0"), Err("division by zero".into()));
+    }
+
+    #[test]
+    fn errors() {
+        let mut cpu = X86Cpu::new();
+        assert_eq!(eval(&mut cpu, "1 / 0"), Err("division by zero".into()));
+        assert_eq!(eval(&mut cpu, "ax % (cx - cx)"), Err("division by zero".into()));
+        assert_eq!(eval(&mut cpu, "1 +"), Err("expression expected".into()));
+        assert_eq!(eval(&mut cpu, "(1"), Err("missing )".into()));
+        assert_eq!(eval(&mut cpu, "xx == 1"), Err("unexpected xx in expression".into()));
+    }
+}
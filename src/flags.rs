@@ -0,0 +1,167 @@
+use crate::decode::Width;
+
+// FLAGS bits: [ ...|O|D|I|T|S|Z|A|P|C ]
+pub const CF: u16 = 0x0001;
+pub const PF: u16 = 0x0004;
+pub const AF: u16 = 0x0010;
+pub const ZF: u16 = 0x0040;
+pub const SF: u16 = 0x0080;
+pub const TF: u16 = 0x0100;
+pub const IF: u16 = 0x0200;
+pub const DF: u16 = 0x0400;
+pub const OF: u16 = 0x0800;
+
+// bits derived from the result of an arithmetic or logic instruction
+pub const ARITH: u16 = CF | PF | AF | ZF | SF | OF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FlagOp {
+    None, // FLAGS is up to date
+    Add,
+    Sub,
+    Logic,
+    Inc, // like Add/Sub, but CF is kept from FLAGS
+    Dec,
+}
+
+// The last flag-setting ALU operation. Arithmetic bits are only computed
+// when something reads them, so most results are never turned into flags.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LazyFlags {
+    pub op: FlagOp,
+    pub width: Width,
+    pub a: u16,
+    pub b: u16,
+    pub carry: u16, // carry/borrow in for ADC/SBB
+    pub res: u16,
+}
+
+impl LazyFlags {
+    pub const NONE: LazyFlags = LazyFlags { op: FlagOp::None, width: Width::Word, a: 0, b: 0, carry: 0, res: 0 };
+
+    // `flags` supplies every bit this operation did not produce
+    pub fn get(&self, f: u16, flags: u16) -> bool {
+        let LazyFlags { op, width, a, b, carry, res } = *self;
+        let sign = width.sign();
+        if op == FlagOp::None || f & ARITH == 0 {
+            return flags & f != 0;
+        }
+        match f {
+            CF => match op {
+                FlagOp::Add => a as u32 + b as u32 + carry as u32 > width.mask() as u32,
+                FlagOp::Sub => b as u32 + carry as u32 > a as u32,
+                FlagOp::Logic => false,
+                _ => flags & CF != 0,
+            },
+            PF => (res as u8).count_ones() & 1 == 0,
+            AF => op != FlagOp::Logic && (a ^ b ^ res) & 0x10 != 0,
+            ZF => res == 0,
+            SF => res & sign != 0,
+            _ => match op {
+                FlagOp::Add | FlagOp::Inc => !(a ^ b) & (a ^ res) & sign != 0,
+                FlagOp::Sub | FlagOp::Dec => (a ^ b) & (a ^ res) & sign != 0,
+                _ => false,
+            },
+        }
+    }
+
+    pub fn apply(&self, flags: u16) -> u16 {
+        if self.op == FlagOp::None {
+            return flags;
+        }
+        let mut res = flags & !ARITH;
+        for f in [CF, PF, AF, ZF, SF, OF] {
+            if self.get(f, flags) {
+                res |= f;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::X86Cpu;
+
+    // the arithmetic flags after `a op b` (plus carry), with CF set before it or not
+    fn flags(op: FlagOp, width: Width, a: u16, b: u16, carry: u16, cf: bool) -> u16 {
+        let res = match op {
+            FlagOp::Add | FlagOp::Inc => a.wrapping_add(b).wrapping_add(carry),
+            FlagOp::Sub | FlagOp::Dec => a.wrapping_sub(b).wrapping_sub(carry),
+            _ => a & b,
+        } & width.mask();
+        let lazy = LazyFlags { op, width, a, b, carry, res };
+        let before = if cf { CF } else { 0 };
+        [CF, PF, AF, ZF, SF, OF].into_iter().filter(|&f| lazy.get(f, before)).fold(0, |all, f| all | f)
+    }
+
+    #[test]
+    fn lazy_flags() {
+        use FlagOp::*;
+        use Width::*;
+        let cases = [
+            // ADD: AF is the carry out of bit 3, OF a signed overflow
+            (Add, Byte, 0x0F, 0x01, 0, false, AF),
+            (Add, Byte, 0x7F, 0x01, 0, false, AF | SF | OF),
+            (Add, Byte, 0xFF, 0x01, 0, false, CF | PF | AF | ZF),
+            (Add, Word, 0x8000, 0x8000, 0, false, CF | PF | ZF | OF),
+            (Add, Word, 0x00FF, 0x0001, 0, false, PF | AF),
+            (Add, Byte, 0xFF, 0x00, 1, false, CF | PF | AF | ZF), // ADC
+            // SUB: AF is the borrow into bit 3, CF the borrow out
+            (Sub, Byte, 0x10, 0x01, 0, false, PF | AF),
+            (Sub, Byte, 0x80, 0x01, 0, false, AF | OF),
+            (Sub, Byte, 0x00, 0x01, 0, false, CF | PF | AF | SF),
+            (Sub, Byte, 0x7F, 0xFF, 0, false, CF | SF | OF),
+            (Sub, Word, 0x8000, 0x0001, 0, false, PF | AF | OF),
+            (Sub, Byte, 0x05, 0x05, 0, true, PF | ZF),
+            (Sub, Byte, 0x00, 0x00, 1, false, CF | PF | AF | SF), // SBB
+            // INC/DEC keep CF, even when they wrap
+            (Inc, Byte, 0xFF, 0x01, 0, true, CF | PF | AF | ZF),
+            (Inc, Byte, 0xFF, 0x01, 0, false, PF | AF | ZF),
+            (Inc, Byte, 0x7F, 0x01, 0, true, CF | AF | SF | OF),
+            (Dec, Byte, 0x00, 0x01, 0, false, PF | AF | SF),
+            (Dec, Word, 0x8000, 0x01, 0, true, CF | PF | AF | OF),
+            // logic ops clear CF, OF and AF
+            (Logic, Byte, 0xF0, 0x0F, 0, true, PF | ZF),
+        ];
+        for (op, width, a, b, carry, cf, expected) in cases {
+            assert_eq!(flags(op, width, a, b, carry, cf), expected, "{:?} {:?} {:#x} {:#x} {}", op, width, a, b, carry);
+        }
+    }
+
+    // CF and OF after running `code` with AX and CX set
+    fn cf_of(code: &[u8], ax: u16, cx: u16) -> (bool, bool) {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0, code);
+        cpu.regs.ax = ax;
+        cpu.regs.cx = cx;
+        cpu.step().unwrap();
+        (cpu.flag(CF), cpu.flag(OF))
+    }
+
+    #[test]
+    fn mul_sets_cf_and_of_for_a_high_half() {
+        const MUL_CL: [u8; 2] = [0xF6, 0xE1];
+        const MUL_CX: [u8; 2] = [0xF7, 0xE1];
+        assert_eq!(cf_of(&MUL_CL, 0x0002, 0x0003), (false, false));
+        assert_eq!(cf_of(&MUL_CL, 0x0010, 0x0010), (true, true));
+        assert_eq!(cf_of(&MUL_CX, 0x7FFF, 0x0001), (false, false));
+        assert_eq!(cf_of(&MUL_CX, 0x8000, 0x0002), (true, true));
+    }
+
+    #[test]
+    fn shifts_by_one_set_of() {
+        const SHL: [u8; 2] = [0xD0, 0xE0];
+        const SHR: [u8; 2] = [0xD0, 0xE8];
+        const SAR: [u8; 2] = [0xD0, 0xF8];
+        const ROL: [u8; 2] = [0xD0, 0xC0];
+        // OF is set when the sign bit changes
+        assert_eq!(cf_of(&SHL, 0x40, 0), (false, true));
+        assert_eq!(cf_of(&SHL, 0xC0, 0), (true, false));
+        assert_eq!(cf_of(&SHR, 0x80, 0), (false, true));
+        assert_eq!(cf_of(&SHR, 0x01, 0), (true, false));
+        assert_eq!(cf_of(&SAR, 0x80, 0), (false, false));
+        assert_eq!(cf_of(&ROL, 0x80, 0), (true, true));
+    }
+}
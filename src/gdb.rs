@@ -0,0 +1,501 @@
+// A GDB remote serial protocol stub, so gdb (with `set architecture i8086`)
+// can drive the simulator over a socket
+//
+// gdb sees the i386 register file, 32 bits a register: eax ecx edx ebx esp
+// ebp esi edi eip eflags cs ss ds es fs gs. Memory and breakpoint addresses
+// are physical, and so is eip: it reads as CS*16+IP, which is where gdb has
+// to look for the code, and writing it moves IP within CS where it can.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::bus::{physical, Bus};
+use crate::cpu::{Access, Budget, Exception, StopReason, Watchpoint, X86Cpu};
+
+/// A byte stream gdb is connected over. Continuing polls it without
+/// blocking, so gdb can interrupt a running guest.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, on)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, on)
+    }
+}
+
+// instructions between checks for an interrupt from gdb
+const POLL_INTERVAL: u64 = 0x10000;
+
+const REGISTERS: usize = 16;
+const EIP: usize = 8;
+
+// Z and z packet types
+const SOFTWARE: u8 = 0;
+const HARDWARE: u8 = 1;
+const WRITE_WATCH: u8 = 2;
+const READ_WATCH: u8 = 3;
+const ACCESS_WATCH: u8 = 4;
+
+/// Serve gdb on `conn` until it detaches, kills the target or hangs up.
+pub fn serve<B: Bus, C: Connection>(cpu: &mut X86Cpu<B>, conn: C) -> io::Result<()> {
+    let last_stop = if cpu.halted { "W00" } else { "S05" }.to_string();
+    let mut stub = Stub { link: Link { conn, ack: true, buf: Vec::new(), pos: 0 }, points: Vec::new(), last_stop };
+    while let Some(packet) = stub.link.packet()? {
+        let packet = String::from_utf8_lossy(&packet).into_owned();
+        let reply = match stub.handle(cpu, &packet)? {
+            Reply::Text(text) => text,
+            Reply::Close(text) => {
+                if let Some(text) = text {
+                    stub.link.send(&text)?;
+                }
+                return Ok(());
+            }
+        };
+        stub.link.send(&reply)?;
+        if packet == "QStartNoAckMode" {
+            stub.link.ack = false;
+        }
+    }
+    Ok(())
+}
+
+enum Reply {
+    Text(String),
+    Close(Option<String>),
+}
+
+// packet framing: $data#checksum, each acknowledged with + until no-ack mode
+struct Link<C> {
+    conn: C,
+    ack: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<C: Connection> Link<C> {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pos == self.buf.len() {
+            self.buf.resize(4096, 0);
+            let n = loop {
+                match self.conn.read(&mut self.buf) {
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    other => break other?,
+                }
+            };
+            self.buf.truncate(n);
+            self.pos = 0;
+            if n == 0 {
+                return Ok(None);
+            }
+        }
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+
+    // the next packet's contents, or None once gdb hangs up
+    fn packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // acks, and interrupts that arrive while already stopped
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let (Some(hi), Some(lo)) = (self.byte()?, self.byte()?) else {
+                return Ok(None);
+            };
+            let sum = std::str::from_utf8(&[hi, lo]).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            let ok = sum == Some(checksum(&data));
+            if self.ack {
+                self.conn.write_all(if ok { b"+" } else { b"-" })?;
+            }
+            if ok || !self.ack {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let framed = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.conn.write_all(framed.as_bytes())?;
+            self.conn.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            loop {
+                match self.byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    // whether gdb has sent an interrupt (or gone away) while the guest runs
+    fn interrupted(&mut self) -> bool {
+        if self.buf[self.pos..].contains(&0x03) {
+            return true;
+        }
+        if self.conn.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0];
+        let interrupted = match self.conn.read(&mut byte) {
+            Ok(0) => true,
+            Ok(_) => {
+                self.buf.push(byte[0]);
+                byte[0] == 0x03
+            }
+            Err(_) => false,
+        };
+        let _ = self.conn.set_nonblocking(false);
+        interrupted
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn stop_reply(stop: &StopReason, points: &[(u8, u32, u32)]) -> String {
+    match stop {
+        StopReason::Halted => "W00".to_string(),
+        StopReason::Watchpoint(hit) => {
+            let end = hit.addr + hit.len as u32;
+            let access = points.iter().any(|&(k, a, l)| k == ACCESS_WATCH && a < end && hit.addr < a.saturating_add(l));
+            let kind = match hit.access {
+                _ if access => "awatch",
+                Access::Read => "rwatch",
+                _ => "watch",
+            };
+            format!("T05{}:{:x};", kind, hit.addr)
+        }
+        StopReason::MemoryFault { .. } => "S0b".to_string(), // SIGSEGV
+        StopReason::UnknownOpcode { .. } | StopReason::Exception(Exception::InvalidOpcode) => "S04".to_string(), // SIGILL
+        StopReason::Exception(Exception::DivideError) => "S08".to_string(), // SIGFPE
+        _ => "S05".to_string(), // SIGTRAP
+    }
+}
+
+struct Stub<C> {
+    link: Link<C>,
+    points: Vec<(u8, u32, u32)>, // Z packet type, address, length
+    last_stop: String,
+}
+
+impl<C: Connection> Stub<C> {
+    fn handle<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, packet: &str) -> io::Result<Reply> {
+        let text = |s: &str| Ok(Reply::Text(s.to_string()));
+        let error = || Ok(Reply::Text("E01".to_string()));
+        let (kind, args) = packet.split_at(packet.len().min(1));
+        match kind {
+            "?" => Ok(Reply::Text(self.last_stop.clone())),
+            "g" => Ok(Reply::Text((0..REGISTERS).map(|n| hex_bytes(&register(cpu, n).to_le_bytes())).collect())),
+            "G" => match decode_hex(args) {
+                Some(bytes) => {
+                    let values: Vec<u32> = bytes.chunks_exact(4).take(REGISTERS)
+                        .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]])).collect();
+                    // eip is worked out against CS, so it goes last, and only
+                    // if gdb changed it: an unchanged one would undo a new CS
+                    let eip = register(cpu, EIP);
+                    for (n, &value) in values.iter().enumerate().filter(|&(n, _)| n != EIP) {
+                        set_register(cpu, n, value);
+                    }
+                    match values.get(EIP) {
+                        Some(&value) if value != eip => set_register(cpu, EIP, value),
+                        _ => {}
+                    }
+                    text("OK")
+                }
+                None => error(),
+            },
+            "p" => match parse_hex(args).map(|n| n as usize) {
+                Some(n) if n < REGISTERS => Ok(Reply::Text(hex_bytes(&register(cpu, n).to_le_bytes()))),
+                _ => error(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, v)| Some((parse_hex(n)? as usize, decode_hex(v)?)));
+                match parsed {
+                    Some((n, v)) if n < REGISTERS && v.len() == 4 => {
+                        set_register(cpu, n, u32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+                        text("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => match args.split_once(',').and_then(|(a, l)| Some((parse_hex(a)?, parse_hex(l)?))) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0..len.min(0x1000)).map(|i| cpu.bus.read_u8(addr.wrapping_add(i) & 0xFFFFF)).collect();
+                    // gdb reading unmapped memory is not a guest fault
+                    cpu.bus.take_fault();
+                    Ok(Reply::Text(hex_bytes(&bytes)))
+                }
+                None => error(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(head, data)| {
+                    let (addr, len) = head.split_once(',')?;
+                    Some((parse_hex(addr)?, parse_hex(len)?, decode_hex(data)?))
+                });
+                match parsed {
+                    Some((addr, len, data)) if data.len() == len as usize => {
+                        // straight to the bus, so the guest's watchpoints stay quiet
+                        cpu.load(addr & 0xFFFFF, &data);
+                        text("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    set_register(cpu, EIP, addr);
+                }
+                let stop = if kind == "s" {
+                    match cpu.run(Budget::Instructions(1)).stop {
+                        StopReason::BudgetExhausted => "S05".to_string(),
+                        stop => stop_reply(&stop, &self.points),
+                    }
+                } else {
+                    self.resume(cpu)
+                };
+                self.last_stop = stop.clone();
+                Ok(Reply::Text(stop))
+            }
+            "Z" | "z" => self.point(cpu, kind == "Z", args),
+            "H" | "T" => text("OK"),
+            "k" => Ok(Reply::Close(None)),
+            "D" => Ok(Reply::Close(Some("OK".to_string()))),
+            _ => match packet {
+                _ if packet.starts_with("qSupported") => text("PacketSize=4000;QStartNoAckMode+"),
+                "QStartNoAckMode" => text("OK"),
+                "qAttached" => text("1"),
+                "qC" => text("QC1"),
+                "qfThreadInfo" => text("m1"),
+                "qsThreadInfo" => text("l"),
+                _ => text(""),
+            },
+        }
+    }
+
+    // run until something stops the guest or gdb interrupts it
+    fn resume<B: Bus>(&mut self, cpu: &mut X86Cpu<B>) -> String {
+        let mut interrupted = false;
+        let mut count = 0u64;
+        let link = &mut self.link;
+        let outcome = cpu.run_until(Budget::Unlimited, |_| {
+            count += 1;
+            interrupted = count.is_multiple_of(POLL_INTERVAL) && link.interrupted();
+            interrupted
+        });
+        if interrupted {
+            // drop the interrupt so it is not seen again, keeping what is still to be read
+            let pending: Vec<u8> = link.buf.drain(link.pos..).filter(|&b| b != 0x03).collect();
+            link.buf.extend(pending);
+            return "S02".to_string(); // SIGINT
+        }
+        stop_reply(&outcome.stop, &self.points)
+    }
+
+    fn point<B: Bus>(&mut self, cpu: &mut X86Cpu<B>, insert: bool, args: &str) -> io::Result<Reply> {
+        let mut fields = args.split(',');
+        let parsed = (|| Some((fields.next()?.parse::<u8>().ok()?, parse_hex(fields.next()?)?, parse_hex(fields.next()?)?)))();
+        let Some((kind, addr, len)) = parsed else {
+            return Ok(Reply::Text("E01".to_string()));
+        };
+        let addr = addr & 0xFFFFF;
+        let breakpoint = |k: u8| k == SOFTWARE || k == HARDWARE;
+        match kind {
+            SOFTWARE | HARDWARE => {
+                let set = self.points.iter().any(|&(k, a, _)| breakpoint(k) && a == addr);
+                if insert {
+                    self.points.push((kind, addr, len));
+                    cpu.add_breakpoint(addr);
+                } else {
+                    self.points.retain(|&p| p != (kind, addr, len));
+                    // both kinds share the CPU's breakpoint set
+                    if set && !self.points.iter().any(|&(k, a, _)| breakpoint(k) && a == addr) {
+                        cpu.remove_breakpoint(addr);
+                    }
+                }
+            }
+            WRITE_WATCH | READ_WATCH | ACCESS_WATCH => {
+                let accesses: &[Access] = match kind {
+                    WRITE_WATCH => &[Access::Write],
+                    READ_WATCH => &[Access::Read],
+                    _ => &[Access::Read, Access::Write],
+                };
+                let position = self.points.iter().position(|&p| p == (kind, addr, len));
+                if insert {
+                    self.points.push((kind, addr, len));
+                } else if let Some(i) = position {
+                    self.points.remove(i);
+                } else {
+                    return Ok(Reply::Text("OK".to_string()));
+                }
+                for &access in accesses {
+                    let watchpoint = Watchpoint { range: addr..addr.saturating_add(len), access, stop: true };
+                    if insert {
+                        cpu.add_watchpoint(watchpoint);
+                    } else {
+                        cpu.remove_watchpoint(&watchpoint);
+                    }
+                }
+            }
+            _ => return Ok(Reply::Text(String::new())),
+        }
+        Ok(Reply::Text("OK".to_string()))
+    }
+}
+
+fn register<B: Bus>(cpu: &X86Cpu<B>, n: usize) -> u32 {
+    let r = &cpu.regs;
+    match n {
+        0 => r.ax as u32,
+        1 => r.cx as u32,
+        2 => r.dx as u32,
+        3 => r.bx as u32,
+        4 => r.sp as u32,
+        5 => r.bp as u32,
+        6 => r.si as u32,
+        7 => r.di as u32,
+        EIP => physical(r.cs, r.ip),
+        9 => cpu.flags() as u32,
+        10 => r.cs as u32,
+        11 => r.ss as u32,
+        12 => r.ds as u32,
+        13 => r.es as u32,
+        _ => 0, // fs and gs
+    }
+}
+
+fn set_register<B: Bus>(cpu: &mut X86Cpu<B>, n: usize, value: u32) {
+    let r = &mut cpu.regs;
+    let v = value as u16;
+    match n {
+        0 => r.ax = v,
+        1 => r.cx = v,
+        2 => r.dx = v,
+        3 => r.bx = v,
+        4 => r.sp = v,
+        5 => r.bp = v,
+        6 => r.si = v,
+        7 => r.di = v,
+        // a physical address: keep CS if it covers it
+        EIP => match value.wrapping_sub(physical(r.cs, 0)) {
+            off @ 0..=0xFFFF => r.ip = off as u16,
+            _ => (r.cs, r.ip) = ((value >> 4) as u16, value as u16 & 0xF),
+        },
+        9 => cpu.set_flags(v),
+        10 => r.cs = v,
+        11 => r.ss = v,
+        12 => r.ds = v,
+        13 => r.es = v,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // send each packet and collect the replies, acknowledging them as gdb does
+    fn client(mut conn: TcpStream, packets: &[String]) -> Vec<String> {
+        let mut replies = Vec::new();
+        for packet in packets {
+            conn.write_all(format!("${}#{:02x}", packet, checksum(packet.as_bytes())).as_bytes()).unwrap();
+            if packet == "k" {
+                break;
+            }
+            let mut framed = Vec::new();
+            let mut byte = [0];
+            while framed.len() < 3 || framed[framed.len() - 3] != b'#' {
+                conn.read_exact(&mut byte).unwrap();
+                if framed.is_empty() && byte[0] != b'$' {
+                    continue; // our packet's ack
+                }
+                framed.push(byte[0]);
+            }
+            conn.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(framed[1..framed.len() - 3].to_vec()).unwrap());
+        }
+        replies
+    }
+
+    // a g reply or G packet: ax cx dx bx sp bp si di eip eflags cs ss ds es fs gs
+    fn registers(values: [u32; REGISTERS]) -> String {
+        values.iter().map(|v| hex_bytes(&v.to_le_bytes())).collect()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut cpu = X86Cpu::new();
+        cpu.load(0x10000, &[0xB8, 0x01, 0x00, 0xBB, 0x02, 0x00, 0xF4]); // mov ax, 1; mov bx, 2; hlt
+        (cpu.regs.cs, cpu.regs.ip) = (0x1000, 0);
+
+        let start = registers([0, 0, 0, 0, 0xFFF0, 0, 0, 0, 0x10000, 0, 0x1000, 0, 0, 0, 0, 0]);
+        // eip moved out of CS's window, with CS and the rest as they were
+        let moved = registers([1, 0, 0, 0, 0xFFF0, 0, 0, 0, 0x500, 0, 0x1000, 0, 0, 0, 0, 0]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script: Vec<String> = [
+            "g", "m10000,7", "Z0,10003,1", "c", "p0", "p8", "z0,10003,1", &format!("G{}", moved),
+            "g", "pa", "P8=03000100", "pa", "c", "?",
+            // addresses past 1 MiB wrap instead of overflowing
+            "mffffffff,2", "Mffffffff,1:00", "Z2,ffffffff,4", "z2,ffffffff,4", "k",
+        ].iter().map(|p| p.to_string()).collect();
+        let gdb = thread::spawn(move || {
+            let conn = TcpStream::connect(addr).unwrap();
+            conn.set_nodelay(true).unwrap();
+            client(conn, &script)
+        });
+        let (conn, _) = listener.accept().unwrap();
+        conn.set_nodelay(true).unwrap();
+        serve(&mut cpu, conn).unwrap();
+        let replies = gdb.join().unwrap();
+
+        assert_eq!(replies, [
+            &start, "b80100bb0200f4", "OK", "S05", "01000000", "03000100", "OK", "OK",
+            &registers([1, 0, 0, 0, 0xFFF0, 0, 0, 0, 0x500, 0, 0x50, 0, 0, 0, 0, 0]),
+            "50000000", "OK", "50000000", "W00", "W00", "0000", "OK", "OK", "OK",
+        ]);
+        // back at the second instruction through CS 0050, which still covers it
+        assert_eq!((cpu.regs.cs, cpu.regs.ip, cpu.regs.bx), (0x50, 0xFB07, 2));
+    }
+}
@@ -0,0 +1,262 @@
+// Intel HEX and Motorola S-record images, as firmware is usually shipped
+
+use std::fmt;
+
+use crate::bus::{Bus, ADDRESS_SPACE};
+use crate::cpu::X86Cpu;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HexError {
+    /// A line that is not a well-formed record.
+    Syntax { line: usize },
+    Checksum { line: usize },
+    UnknownRecord { line: usize, kind: u8 },
+    /// Data outside the 1 MiB address space.
+    OutOfRange { line: usize, addr: u32 },
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HexError::Syntax { line } => write!(f, "line {}: malformed record", line),
+            HexError::Checksum { line } => write!(f, "line {}: checksum mismatch", line),
+            HexError::UnknownRecord { line, kind } => write!(f, "line {}: unknown record type {:X}", line, kind),
+            HexError::OutOfRange { line, addr } => write!(f, "line {}: address 0x{:X} is outside memory", line, addr),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+/// Where execution starts, from a start-address record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Start {
+    SegOff(u16, u16),
+    Linear(u32),
+}
+
+/// The contents of an image file: runs of bytes with their physical address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HexImage {
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    pub start: Option<Start>,
+}
+
+impl HexImage {
+    /// Write the data into memory and point CS:IP at the start address, if any.
+    /// A linear start address is split into a 64 KiB-aligned CS and IP.
+    pub fn load<B: Bus>(&self, cpu: &mut X86Cpu<B>) {
+        for (addr, data) in &self.chunks {
+            cpu.load(*addr, data);
+        }
+        match self.start {
+            Some(Start::SegOff(cs, ip)) => (cpu.regs.cs, cpu.regs.ip) = (cs, ip),
+            Some(Start::Linear(addr)) => (cpu.regs.cs, cpu.regs.ip) = ((addr >> 4) as u16 & 0xF000, addr as u16),
+            None => {}
+        }
+    }
+
+    fn push(&mut self, line: usize, addr: u32, data: &[u8]) -> Result<(), HexError> {
+        if addr as usize + data.len() > ADDRESS_SPACE {
+            return Err(HexError::OutOfRange { line, addr });
+        }
+        self.chunks.push((addr, data.to_vec()));
+        Ok(())
+    }
+}
+
+// the hex digits after the record mark, as bytes
+fn record_bytes(text: &str, line: usize) -> Result<Vec<u8>, HexError> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(HexError::Syntax { line });
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| HexError::Syntax { line }))
+        .collect()
+}
+
+fn be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32)
+}
+
+/// Parse Intel HEX: data (00), end of file (01), extended segment address
+/// (02), start segment address (03), extended linear address (04) and start
+/// linear address (05) records. The offset within a record wraps at 64 KiB.
+pub fn parse_ihex(text: &str) -> Result<HexImage, HexError> {
+    let mut image = HexImage::default();
+    let mut base = 0u32;
+    for (i, raw) in text.lines().enumerate() {
+        let (line, raw) = (i + 1, raw.trim());
+        if raw.is_empty() {
+            continue;
+        }
+        let rec = record_bytes(raw.strip_prefix(':').ok_or(HexError::Syntax { line })?, line)?;
+        if rec.len() < 5 || rec.len() != rec[0] as usize + 5 {
+            return Err(HexError::Syntax { line });
+        }
+        if rec.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(HexError::Checksum { line });
+        }
+        let (offset, kind, data) = (be(&rec[1..3]) as u16, rec[3], &rec[4..rec.len() - 1]);
+        let expect_len = |n: usize| if data.len() == n { Ok(()) } else { Err(HexError::Syntax { line }) };
+        match kind {
+            0x00 => {
+                // split where the offset wraps, so each chunk is contiguous
+                let split = (0x10000 - offset as usize).min(data.len());
+                image.push(line, base + offset as u32, &data[..split])?;
+                if split < data.len() {
+                    image.push(line, base, &data[split..])?;
+                }
+            }
+            0x01 => break,
+            0x02 => { expect_len(2)?; base = be(data) << 4; }
+            0x03 => { expect_len(4)?; image.start = Some(Start::SegOff(be(&data[..2]) as u16, be(&data[2..]) as u16)); }
+            0x04 => { expect_len(2)?; base = be(data) << 16; }
+            0x05 => { expect_len(4)?; image.start = Some(Start::Linear(be(data))); }
+            _ => return Err(HexError::UnknownRecord { line, kind }),
+        }
+    }
+    Ok(image)
+}
+
+/// Parse Motorola S-records: S1/S2/S3 data with 16, 24 and 32-bit
+/// addresses and S9/S8/S7 start addresses. S0 headers and S5/S6 counts are
+/// checked but otherwise ignored.
+pub fn parse_srec(text: &str) -> Result<HexImage, HexError> {
+    let mut image = HexImage::default();
+    for (i, raw) in text.lines().enumerate() {
+        let (line, raw) = (i + 1, raw.trim());
+        if raw.is_empty() {
+            continue;
+        }
+        let rest = raw.strip_prefix(['S', 's']).ok_or(HexError::Syntax { line })?;
+        let kind = rest.bytes().next().filter(u8::is_ascii_digit).ok_or(HexError::Syntax { line })? - b'0';
+        let rec = record_bytes(&rest[1..], line)?;
+        if rec.is_empty() || rec.len() != rec[0] as usize + 1 {
+            return Err(HexError::Syntax { line });
+        }
+        if rec.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0xFF {
+            return Err(HexError::Checksum { line });
+        }
+        let addr_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(HexError::UnknownRecord { line, kind }),
+        };
+        if rec.len() < addr_len + 2 {
+            return Err(HexError::Syntax { line });
+        }
+        let addr = be(&rec[1..1 + addr_len]);
+        let data = &rec[1 + addr_len..rec.len() - 1];
+        match kind {
+            1..=3 => image.push(line, addr, data)?,
+            7..=9 => image.start = Some(Start::Linear(addr)),
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an Intel HEX record with its length and checksum filled in
+    fn ihex(offset: u16, kind: u8, data: &[u8]) -> String {
+        let mut rec = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        rec.extend_from_slice(data);
+        rec.push(rec.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b)));
+        format!(":{}\n", rec.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+    }
+
+    // an S-record with its count and checksum filled in
+    fn srec(kind: u8, addr: &[u8], data: &[u8]) -> String {
+        let mut rec = vec![(addr.len() + data.len() + 1) as u8];
+        rec.extend_from_slice(addr);
+        rec.extend_from_slice(data);
+        rec.push(!rec.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+        format!("S{}{}\n", kind, rec.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+    }
+
+    #[test]
+    fn ihex_data_and_extended_addresses() {
+        let text = [
+            ihex(0x0100, 0x00, &[1, 2, 3]),
+            ihex(0, 0x02, &[0x10, 0x00]),
+            ihex(0xFFFE, 0x00, &[4, 5, 6, 7]), // wraps within the segment
+            ihex(0, 0x04, &[0x00, 0x0F]),
+            ihex(0x0010, 0x00, &[8]),
+            ihex(0, 0x01, &[]),
+            ihex(0, 0x00, &[9]), // past the end of file
+        ].concat();
+        let image = parse_ihex(&text).unwrap();
+        assert_eq!(image.chunks, [
+            (0x00100, vec![1, 2, 3]),
+            (0x1FFFE, vec![4, 5]),
+            (0x10000, vec![6, 7]),
+            (0xF0010, vec![8]),
+        ]);
+        assert_eq!(image.start, None);
+    }
+
+    #[test]
+    fn ihex_checksums_are_checked() {
+        let mut text = ihex(0, 0x00, &[1, 2]);
+        assert!(parse_ihex(&text).is_ok());
+        text.replace_range(9..11, "03");
+        assert_eq!(parse_ihex(&text), Err(HexError::Checksum { line: 1 }));
+        assert_eq!(parse_ihex(&ihex(0, 0x06, &[])), Err(HexError::UnknownRecord { line: 1, kind: 6 }));
+        assert_eq!(parse_ihex(":0000"), Err(HexError::Syntax { line: 1 }));
+    }
+
+    #[test]
+    fn ihex_start_records() {
+        let mut cpu = X86Cpu::new();
+        let image = parse_ihex(&ihex(0, 0x03, &[0x12, 0x34, 0x56, 0x78])).unwrap();
+        assert_eq!(image.start, Some(Start::SegOff(0x1234, 0x5678)));
+        image.load(&mut cpu);
+        assert_eq!((cpu.regs.cs, cpu.regs.ip), (0x1234, 0x5678));
+
+        let image = parse_ihex(&ihex(0, 0x05, &[0x00, 0x01, 0x23, 0x45])).unwrap();
+        assert_eq!(image.start, Some(Start::Linear(0x12345)));
+        image.load(&mut cpu);
+        assert_eq!((cpu.regs.cs, cpu.regs.ip), (0x1000, 0x2345));
+    }
+
+    #[test]
+    fn srec_data_and_start_records() {
+        let text = [
+            srec(0, &[0, 0], b"hdr"),
+            srec(1, &[0x01, 0x00], &[1, 2]),
+            srec(2, &[0x01, 0x20, 0x00], &[3]),
+            srec(3, &[0x00, 0x0F, 0xFF, 0xF0], &[4, 5]),
+            srec(5, &[0, 3], &[]),
+            srec(9, &[0x7C, 0x00], &[]),
+        ].concat();
+        let image = parse_srec(&text).unwrap();
+        assert_eq!(image.chunks, [(0x00100, vec![1, 2]), (0x12000, vec![3]), (0xFFFF0, vec![4, 5])]);
+        assert_eq!(image.start, Some(Start::Linear(0x7C00)));
+
+        assert_eq!(parse_srec(&srec(8, &[0x0F, 0xFF, 0xF0], &[])).unwrap().start, Some(Start::Linear(0xFFFF0)));
+        let image = parse_srec(&srec(7, &[0x00, 0x01, 0x23, 0x45], &[])).unwrap();
+        let mut cpu = X86Cpu::new();
+        image.load(&mut cpu);
+        assert_eq!((cpu.regs.cs, cpu.regs.ip), (0x1000, 0x2345));
+    }
+
+    #[test]
+    fn srec_checksums_are_checked() {
+        let mut text = srec(1, &[0, 0], &[1]);
+        text.replace_range(8..10, "02");
+        assert_eq!(parse_srec(&text), Err(HexError::Checksum { line: 1 }));
+        assert_eq!(parse_srec(&srec(4, &[0, 0], &[])), Err(HexError::UnknownRecord { line: 1, kind: 4 }));
+    }
+
+    #[test]
+    fn data_past_1_mib_is_out_of_range() {
+        assert_eq!(parse_srec(&srec(3, &[0x00, 0x0F, 0xFF, 0xFF], &[1, 2])), Err(HexError::OutOfRange { line: 1, addr: 0xFFFFF }));
+        let text = [ihex(0, 0x04, &[0x00, 0x10]), ihex(0, 0x00, &[1])].concat();
+        assert_eq!(parse_ihex(&text), Err(HexError::OutOfRange { line: 2, addr: 0x100000 }));
+    }
+}
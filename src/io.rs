@@ -0,0 +1,89 @@
+// Port I/O devices and the registry that routes IN/OUT to them
+
+use std::ops::RangeInclusive;
+
+// Devices see absolute port numbers, so one device can decode several ports
+pub trait IoDevice {
+    fn read_u8(&mut self, port: u16) -> u8;
+    fn write_u8(&mut self, port: u16, val: u8);
+
+    // a word access is two byte accesses unless the device says otherwise
+    fn read_u16(&mut self, port: u16) -> u16 {
+        let low = self.read_u8(port) as u16;
+        let high = self.read_u8(port.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    fn write_u16(&mut self, port: u16, val: u16) {
+        self.write_u8(port, (val & 0xFF) as u8);
+        self.write_u8(port.wrapping_add(1), (val >> 8) as u8);
+    }
+}
+
+#[derive(Default)]
+pub struct PortMap {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn IoDevice>)>,
+    pub log_unclaimed: bool, // report accesses no device answered on stderr
+}
+
+impl PortMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // later mappings take precedence where ranges overlap
+    pub fn map(&mut self, ports: RangeInclusive<u16>, device: Box<dyn IoDevice>) {
+        self.devices.push((ports, device));
+    }
+
+    fn find(&mut self, port: u16) -> Option<usize> {
+        self.devices.iter().rposition(|(ports, _)| ports.contains(&port))
+    }
+
+    pub fn read_u8(&mut self, port: u16) -> u8 {
+        match self.find(port) {
+            Some(i) => self.devices[i].1.read_u8(port),
+            None => {
+                if self.log_unclaimed {
+                    eprintln!("IN from unclaimed port 0x{:04X}", port);
+                }
+                0xFF
+            }
+        }
+    }
+
+    pub fn write_u8(&mut self, port: u16, val: u8) {
+        match self.find(port) {
+            Some(i) => self.devices[i].1.write_u8(port, val),
+            None => {
+                if self.log_unclaimed {
+                    eprintln!("OUT 0x{:02X} to unclaimed port 0x{:04X}", val, port);
+                }
+            }
+        }
+    }
+
+    // a word access only goes to a device as a word if it claims both ports
+    pub fn read_u16(&mut self, port: u16) -> u16 {
+        let next = port.wrapping_add(1);
+        match (self.find(port), self.find(next)) {
+            (Some(i), Some(j)) if i == j => self.devices[i].1.read_u16(port),
+            _ => {
+                let low = self.read_u8(port) as u16;
+                let high = self.read_u8(next) as u16;
+                (high << 8) | low
+            }
+        }
+    }
+
+    pub fn write_u16(&mut self, port: u16, val: u16) {
+        let next = port.wrapping_add(1);
+        match (self.find(port), self.find(next)) {
+            (Some(i), Some(j)) if i == j => self.devices[i].1.write_u16(port, val),
+            _ => {
+                self.write_u8(port, (val & 0xFF) as u8);
+                self.write_u8(next, (val >> 8) as u8);
+            }
+        }
+    }
+}
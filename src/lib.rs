@@ -0,0 +1,36 @@
+//! A real-mode 8086 simulator for embedding in other programs.
+//!
+//! ```
+//! use x86_simulator::{Budget, StopReason, X86Cpu};
+//!
+//! let mut cpu = X86Cpu::new();
+//! cpu.load(0, &[0xB8, 0x2A, 0x00, 0xF4]); // mov ax, 42; hlt
+//! let outcome = cpu.run(Budget::Instructions(1000));
+//! assert_eq!(outcome.stop, StopReason::Halted);
+//! assert_eq!(cpu.regs.ax, 42);
+//! ```
+//!
+//! Memory and ports are reached through a [`Bus`]. [`Ram`] is the default;
+//! [`MemoryMap`] lays ROM, MMIO devices and a [`PortMap`] over it, and hosts
+//! can implement [`Bus`] themselves. Guest interrupts can be served by host
+//! code with [`X86Cpu::hook_interrupt`].
+
+pub mod asm;
+pub mod boot;
+pub mod bus;
+mod cpu;
+pub mod debugger;
+pub mod decode;
+pub mod disasm;
+pub mod dos;
+pub mod expr;
+pub mod flags;
+pub mod gdb;
+pub mod hexfile;
+pub mod io;
+pub mod modrm;
+mod timing;
+
+pub use bus::{physical, Bus, MemoryMap, MmioDevice, Ram};
+pub use cpu::{Access, Budget, CpuModel, Exception, Registers, RunOutcome, StopReason, WatchHit, Watchpoint, X86Cpu};
+pub use io::{IoDevice, PortMap};
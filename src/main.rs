@@ -1,156 +1,351 @@
-const MEM_SIZE: usize = 0x10000; //     64 kilobytes
-const STACK_START: u16 = 0xFFF0;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process::ExitCode;
+use std::{env, fs, io};
 
+use x86_simulator::boot::{self, Disk};
+use x86_simulator::debugger::{self, Debugger};
+use x86_simulator::disasm::{self, Syntax};
+use x86_simulator::{asm, dos, gdb, hexfile};
+use x86_simulator::{physical, Budget, CpuModel, Exception, StopReason, X86Cpu};
 
-#[derive(Default, Debug)]
-struct Registers {
-    ax: u16, bx: u16, cx: u16, dx: u16,
-    si: u16, di: u16, sp: u16, bp: u16,
-    ip: u16,
-    flags: u16, // [ ...|O|D|I|T|S|Z|A|P|C ]
-}
+const USAGE: &str = "\
+usage: x86_simulator [options] <image>
+       x86_simulator disasm [--syntax S] [--org OFF] [--skip N] [--length N] <file>
+       x86_simulator asm <source> [-o <output>]
+
+Loads a raw binary, DOS .COM/.EXE file, Intel HEX (.hex, .ihx) or S-record
+(.srec, .s19, .s28, .s37, .mot) image, or assembly source (.asm), and runs it
+until it halts or faults. Source with `org 100h` runs as a .COM program;
+other source is loaded at the SEG of --load and its org.
+
+options:
+  --load SEG:OFF       load address (default 0000:0000); a DOS program gets a
+                       PSP at SEG:0000 (default SEG 1000)
+  --args TEXT          command tail for a DOS program
+  --boot               treat the image as a floppy or hard-disk image and boot
+                       its first sector at 0000:7C00 like a PC BIOS
+  --drive HEX          BIOS drive number to boot from (default 00, or 80 when
+                       the image is not a standard floppy size)
+  --entry SEG:OFF      initial CS:IP (default: the load address, as DOS sets it,
+                       or a HEX/S-record start address, else FFFF:0000)
+  --stack SEG:OFF      initial SS:SP (default 0000:FFF0, or as DOS sets it)
+  --steps N            stop after N instructions
+  --cycles N           stop after about N 8086 clocks
+  --trace N            0 = quiet, 1 = each instruction, 2 = and the registers
+  --syntax S           disassembly syntax for traces and the debugger: intel
+                       (default), nasm or att
+  -v                   raise the trace level by one
+  --regs               print the registers on exit
+  --dump SEG:OFF+LEN   hex dump LEN bytes of memory on exit; may be repeated
+  --model 8086|186     CPU model (default 8086)
+  --guest-exceptions   enter the guest's handlers for CPU exceptions instead of stopping
+  --debug              load the image and start the interactive debugger on stdin
+  --gdb ADDR           load the image and wait for gdb to connect on ADDR:
+                       HOST:PORT or PORT for TCP, or a path for a Unix socket
+
+disasm lists the instructions in a raw file, taking its first byte (after
+skipping N) to be at offset OFF (default 0, or 100 for a .com file).
+asm assembles NASM-style source to a flat binary (default: the source name
+with .bin).
 
-struct X86Cpu {
-    regs: Registers,
-    memory: Box<[u8; MEM_SIZE]>,
-    halted: bool,
+Addresses are hexadecimal; N and LEN are decimal, or hexadecimal with 0x.
+
+exit status: 0 halted, 1 fault or exception, 2 usage or I/O error, 3 limit reached
+";
+
+#[derive(Default)]
+struct Options {
+    image: String,
+    load: Option<(u16, u16)>,
+    entry: Option<(u16, u16)>,
+    stack: Option<(u16, u16)>,
+    args: String,
+    boot: bool,
+    drive: Option<u8>,
+    budget: Option<Budget>,
+    trace: u32,
+    syntax: Syntax,
+    regs: bool,
+    dumps: Vec<(u32, u32)>,
+    model: CpuModel,
+    guest_exceptions: bool,
+    debug: bool,
+    gdb: Option<String>,
 }
 
-impl X86Cpu {
-    fn new() -> Self {
-        let mut cpu = X86Cpu {
-            regs: Registers::default(),
-            memory: Box::new([0; MEM_SIZE]),
-            halted: false,
-        };
-        cpu.regs.sp = STACK_START;
-        cpu
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut image = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--load" => opts.load = Some(parse_seg_off(value()?)?),
+            "--entry" => opts.entry = Some(parse_seg_off(value()?)?),
+            "--stack" => opts.stack = Some(parse_seg_off(value()?)?),
+            "--args" => opts.args = value()?.clone(),
+            "--boot" => opts.boot = true,
+            "--drive" => {
+                let drive = parse_hex(value()?)?;
+                opts.drive = Some(u8::try_from(drive).map_err(|_| format!("bad drive number {:X}", drive))?);
+            }
+            "--steps" => opts.budget = Some(Budget::Instructions(parse_count(value()?)?)),
+            "--cycles" => opts.budget = Some(Budget::Cycles(parse_count(value()?)?)),
+            "--trace" => opts.trace = parse_count(value()?)? as u32,
+            "-v" => opts.trace += 1,
+            "--syntax" => opts.syntax = parse_syntax(value()?)?,
+            "--regs" => opts.regs = true,
+            "--dump" => opts.dumps.push(parse_range(value()?)?),
+            "--model" => opts.model = match value()?.as_str() {
+                "8086" => CpuModel::I8086,
+                "186" | "80186" => CpuModel::I80186,
+                m => return Err(format!("unknown CPU model {}", m)),
+            },
+            "--guest-exceptions" => opts.guest_exceptions = true,
+            "--debug" => opts.debug = true,
+            "--gdb" => opts.gdb = Some(value()?.clone()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if image.is_none() => image = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
     }
-    fn fetch_u8(&mut self) -> u8 {
-        let val = self.memory[self.regs.ip as usize];
-        self.regs.ip += 1;
-        val
+    opts.image = image.ok_or("no image given")?;
+    Ok(opts)
+}
+
+fn parse_syntax(s: &str) -> Result<Syntax, String> {
+    match s {
+        "intel" => Ok(Syntax::Intel),
+        "nasm" => Ok(Syntax::Nasm),
+        "att" | "gas" => Ok(Syntax::Att),
+        _ => Err(format!("unknown syntax {}", s)),
     }
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("bad hex number {}", s))
+}
 
-    fn fetch_u16(&mut self) -> u16 {
-        let low = self.fetch_u8() as u16;
-        let high = self.fetch_u8() as u16;
-        (high << 8) | low
+fn parse_count(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
     }
+    .map_err(|_| format!("bad number {}", s))
+}
 
-    fn push(&mut self, val: u16) {      //for stack
-        self.regs.sp -= 2;
-        let addr = self.regs.sp as usize;
-        self.memory[addr] = (val & 0xFF) as u8;
-        self.memory[addr + 1] = (val >> 8) as u8;
+fn parse_seg_off(s: &str) -> Result<(u16, u16), String> {
+    let (seg, off) = s.split_once(':').ok_or_else(|| format!("expected SEG:OFF, got {}", s))?;
+    let (seg, off) = (parse_hex(seg)?, parse_hex(off)?);
+    if seg > 0xFFFF || off > 0xFFFF {
+        return Err(format!("{} is out of range", s));
     }
+    Ok((seg as u16, off as u16))
+}
+
+// SEG:OFF+LEN or a linear ADDR+LEN
+fn parse_range(s: &str) -> Result<(u32, u32), String> {
+    let (addr, len) = s.split_once('+').ok_or_else(|| format!("expected ADDR+LEN, got {}", s))?;
+    let addr = if addr.contains(':') {
+        let (seg, off) = parse_seg_off(addr)?;
+        physical(seg, off)
+    } else {
+        parse_hex(addr)?
+    };
+    Ok((addr, parse_count(len)? as u32))
+}
 
-    fn pop(&mut self) -> u16 {
-        let addr = self.regs.sp as usize;
-        let val = ((self.memory[addr + 1] as u16) << 8) | (self.memory[addr] as u16);
-        self.regs.sp += 2;
-        val
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn trace(cpu: &mut X86Cpu, level: u32, syntax: Syntax) {
+    println!("{}", debugger::listing(cpu, cpu.regs.cs, cpu.regs.ip, syntax).0);
+    if level > 1 {
+        print!("{}", debugger::registers(cpu));
     }
+}
 
-    fn set_sz(&mut self, val: u16) {
-        if val == 0 {
-            self.regs.flags |= 0x40;
+fn run(opts: &Options) -> Result<ExitCode, String> {
+    let mut image = fs::read(&opts.image).map_err(|e| format!("{}: {}", opts.image, e))?;
+    let ext = opts.image.rsplit_once('.').map_or(String::new(), |(_, ext)| ext.to_ascii_lowercase());
+    // source is assembled first; `org 100h` makes it a .COM program
+    let mut origin = None;
+    if ext == "asm" {
+        let program = asm::assemble(&String::from_utf8_lossy(&image)).map_err(|e| format!("{}: {}", opts.image, e))?;
+        (image, origin) = (program.bytes, Some(program.origin));
+    }
+    let mut cpu = X86Cpu::new();
+    cpu.model = opts.model;
+    // like DOS, go by the signature rather than the extension for executables
+    let exe = image.starts_with(b"MZ") || image.starts_with(b"ZM");
+    let text = || String::from_utf8_lossy(&image).into_owned();
+    let hex = match ext.as_str() {
+        "hex" | "ihx" => Some(hexfile::parse_ihex(&text())),
+        "srec" | "s19" | "s28" | "s37" | "mot" => Some(hexfile::parse_srec(&text())),
+        _ => None,
+    };
+    if opts.boot {
+        let mut disk = Disk::new(image);
+        disk.drive = opts.drive.unwrap_or(disk.drive);
+        boot::boot(&mut cpu, disk, io::stdout()).map_err(|e| format!("{}: {}", opts.image, e))?;
+    } else if let Some(hex) = hex {
+        let hex = hex.map_err(|e| format!("{}: {}", opts.image, e))?;
+        // without a start record, begin where an 8086 does after reset
+        (cpu.regs.cs, cpu.regs.ip) = (0xFFFF, 0);
+        hex.load(&mut cpu);
+    } else if exe || ext == "com" || origin == Some(0x100) {
+        let seg = opts.load.map_or(0x1000, |(seg, _)| seg);
+        let loaded = if exe {
+            dos::load_exe(&mut cpu, &image, seg, &opts.args)
         } else {
-            self.regs.flags &= !0x40;
+            dos::load_com(&mut cpu, &image, seg, &opts.args)
+        };
+        loaded.map_err(|e| format!("{}: {}", opts.image, e))?;
+        dos::hook_services(&mut cpu, io::stdout());
+    } else {
+        let (seg, off) = opts.load.unwrap_or((0, 0));
+        let off = origin.unwrap_or(off);
+        let base = physical(seg, off);
+        if base as usize + image.len() > 0x100000 {
+            return Err(format!("{} does not fit in memory at {:04X}:{:04X}", opts.image, seg, off));
         }
-        if val & 0x8000 != 0 {
-            self.regs.flags |= 0x80;
-        } else {
-            self.regs.flags &= !0x80;
+        cpu.load(base, &image);
+        (cpu.regs.cs, cpu.regs.ip) = (seg, off);
+        (cpu.regs.ds, cpu.regs.es) = (seg, seg);
+    }
+    if let Some((cs, ip)) = opts.entry {
+        (cpu.regs.cs, cpu.regs.ip) = (cs, ip);
+    }
+    if let Some((ss, sp)) = opts.stack {
+        (cpu.regs.ss, cpu.regs.sp) = (ss, sp);
+    }
+    if !opts.guest_exceptions {
+        for exc in [Exception::DivideError, Exception::Breakpoint, Exception::Overflow, Exception::InvalidOpcode] {
+            cpu.stop_on_exception(exc, true);
         }
     }
 
-    fn step(&mut self) {
-        if self.halted { return; }
-        let opcode = self.fetch_u8();
-        match opcode {
-            // MOV reg imm16
-            0xB8 => { self.regs.ax = self.fetch_u16(); }
-            0xBB => { self.regs.bx = self.fetch_u16(); }
-            0xB9 => { self.regs.cx = self.fetch_u16(); }
-            0x40 => { self.regs.ax = self.regs.ax.wrapping_add(1); self.set_sz(self.regs.ax); }
-            0x48 => { self.regs.ax = self.regs.ax.wrapping_sub(1); self.set_sz(self.regs.ax); }
-            0x49 => { self.regs.cx = self.regs.cx.wrapping_sub(1); self.set_sz(self.regs.cx); }
-            0x50 => { let v = self.regs.ax; self.push(v); }
-            0x58 => { self.regs.ax = self.pop(); }
-            0xF7 => {
-                let next = self.fetch_u8();
-                if next == 0xE1 {
-                    let res = (self.regs.ax as u32) * (self.regs.cx as u32);
-                    self.regs.ax = res as u16;
-                    self.regs.dx = (res >> 16) as u16;
-                }
-            }
-
-            // CMP CX
-            0x81 => {
-                let next = self.fetch_u8();
-                if next == 0xF9 {
-                    let imm = self.fetch_u16();
-                    let res = self.regs.cx.wrapping_sub(imm);
-                    self.set_sz(res);
-                }
-            }
-
-            // JNZ
-            0x75 => {
-                let offset = self.fetch_u8() as i8;
-                if (self.regs.flags & 0x40) == 0 {
-                    self.regs.ip = (self.regs.ip as i16 + offset as i16) as u16;
-                }
-            }
+    if opts.debug {
+        let mut debugger = Debugger::new();
+        debugger.syntax = opts.syntax;
+        debugger.repl(&mut cpu, io::stdin().lock(), io::stdout()).map_err(|e| e.to_string())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(addr) = &opts.gdb {
+        serve_gdb(&mut cpu, addr).map_err(|e| format!("gdb on {}: {}", addr, e))?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if opts.trace > 0 {
+        trace(&mut cpu, opts.trace, opts.syntax);
+    }
+    let outcome = cpu.run_until(opts.budget.unwrap_or(Budget::Unlimited), |cpu| {
+        if opts.trace > 0 {
+            trace(cpu, opts.trace, opts.syntax);
+        }
+        false
+    });
+    eprintln!("stopped: {} after {} instructions, {} clocks", outcome.stop, outcome.retired, outcome.cycles);
 
-            // HLT
-            0xF4 => { self.halted = true; }
+    if opts.regs {
+        print!("{}", debugger::registers(&cpu));
+    }
+    for &(addr, len) in &opts.dumps {
+        print!("{}", debugger::hex_dump(&mut cpu, addr, len));
+    }
+    Ok(ExitCode::from(match outcome.stop {
+        StopReason::Halted => 0,
+        StopReason::BudgetExhausted => 3,
+        _ => 1,
+    }))
+}
 
-            _ => {
-                println!("Unknown opcode: 0x{:02X} at IP: 0x{:04X}", opcode, self.regs.ip.wrapping_sub(1));
-                self.halted = true;
-            }
+// one gdb session, over TCP or a Unix socket
+fn serve_gdb(cpu: &mut X86Cpu, addr: &str) -> io::Result<()> {
+    if addr.contains('/') {
+        #[cfg(unix)]
+        {
+            let listener = UnixListener::bind(addr)?;
+            eprintln!("waiting for gdb on {}", addr);
+            let (conn, _) = listener.accept()?;
+            let served = gdb::serve(cpu, conn);
+            let _ = fs::remove_file(addr);
+            return served;
         }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "no Unix sockets here"));
     }
+    let addr = if addr.contains(':') { addr.to_string() } else { format!("127.0.0.1:{}", addr) };
+    let listener = TcpListener::bind(&addr)?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (conn, _) = listener.accept()?;
+    conn.set_nodelay(true)?;
+    gdb::serve(cpu, conn)
 }
 
-
-fn load_factorial_program(cpu: &mut X86Cpu) {
-    let program: Vec<u8> = vec![
-        0xB8, 0x01, 0x00,
-        0xB9, 0x05, 0x00,
-        0xF7, 0xE1,
-        0x49,
-        0x81, 0xF9, 0x01, 0x00,
-        0x75, 0xF7,
-        0x50,
-        0xF4
-    ];
-
-    for (i, &byte) in program.iter().enumerate() {
-        cpu.memory[i] = byte;
+fn disassemble(args: &[String]) -> Result<ExitCode, String> {
+    let (mut syntax, mut org, mut skip, mut length) = (Syntax::Intel, None, 0, None);
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--syntax" => syntax = parse_syntax(value()?)?,
+            "--org" => org = Some(u16::try_from(parse_hex(value()?)?).map_err(|_| "--org is out of range")?),
+            "--skip" => skip = parse_count(value()?)? as usize,
+            "--length" => length = Some(parse_count(value()?)? as usize),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if file.is_none() => file = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
     }
+    let file = file.ok_or("no file given")?;
+    let code = fs::read(&file).map_err(|e| format!("{}: {}", file, e))?;
+    let com = file.to_ascii_lowercase().ends_with(".com");
+    let start = skip.min(code.len());
+    let end = length.map_or(code.len(), |n| (start + n).min(code.len()));
+    let org = org.unwrap_or(if com { 0x100 } else { 0 });
+    for line in disasm::disassemble(&code[start..end], org, syntax) {
+        println!("{:04X}  {:<20} {}", line.offset, hex_bytes(&line.bytes), line.text);
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
+fn assemble(args: &[String]) -> Result<ExitCode, String> {
+    let (source, output) = match args {
+        [source] => (source, None),
+        [source, o, output] | [o, output, source] if o == "-o" => (source, Some(output.clone())),
+        _ => return Err("usage: asm <source> [-o <output>]".into()),
+    };
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let program = asm::assemble(&text).map_err(|e| format!("{}: {}", source, e))?;
+    let output = output.unwrap_or_else(|| {
+        let stem = source.rsplit_once('.').map_or(source.as_str(), |(stem, _)| stem);
+        format!("{}.bin", stem)
+    });
+    fs::write(&output, &program.bytes).map_err(|e| format!("{}: {}", output, e))?;
+    Ok(ExitCode::SUCCESS)
+}
 
-fn main() {
-    println!("--- x86 Real Mode Simulator ---");
-    let mut cpu = X86Cpu::new();
-    load_factorial_program(&mut cpu);
-    println!("Calculating 5! (Factorial of 5)...");
-    let mut steps = 0;
-
-    while !cpu.halted && steps < 50 {
-        cpu.step();
-        steps += 1;
-        println!("Step {:02} | IP: 0x{:04X} | AX: {:5} | CX: {:5}",
-                 steps, cpu.regs.ip, cpu.regs.ax, cpu.regs.cx);
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "-h" || a == "--help") {
+        print!("{}", USAGE);
+        return if args.is_empty() { ExitCode::from(2) } else { ExitCode::SUCCESS };
     }
-
-    let result = cpu.pop();
-    println!("\nSimulation Halted.");
-    println!("Final Factorial Result on Stack: {}", result);
-
-}
\ No newline at end of file
+    let result = match args[0].as_str() {
+        "disasm" => disassemble(&args[1..]),
+        "asm" => assemble(&args[1..]),
+        _ => parse_args(&args).and_then(|opts| run(&opts)),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("x86_simulator: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
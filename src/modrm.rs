@@ -0,0 +1,68 @@
+// ModR/M byte decoding for 16-bit addressing
+//
+//  7  6 5   3 2  0
+// [ mod | reg | r/m ]
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base {
+    BxSi, BxDi, BpSi, BpDi,
+    Si, Di, Bp, Bx,
+    None, // direct [disp16]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub seg: Option<u8>, // segment override prefix; DS, or SS for BP-based addresses, otherwise
+    pub base: Base,
+    pub disp: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    Reg(u8), // reg-field number, see Registers::get16 and Registers::get8
+    Seg(u8), // 0=ES 1=CS 2=SS 3=DS
+    Mem(Address),
+    Imm(u16),
+    Rel(u16),      // resolved branch target
+    Far(u16, u16), // segment, offset
+    Cl,            // shift count
+    Dx,            // port number
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ModRm {
+    pub reg: u8,
+    pub rm: Operand,
+}
+
+impl ModRm {
+    // `fetch` supplies the displacement bytes that follow the ModR/M byte
+    pub fn decode(byte: u8, mut fetch: impl FnMut() -> u8) -> ModRm {
+        let md = byte >> 6;
+        let reg = (byte >> 3) & 7;
+        let rm = byte & 7;
+        if md == 3 {
+            return ModRm { reg, rm: Operand::Reg(rm) };
+        }
+
+        let base = match rm {
+            0 => Base::BxSi,
+            1 => Base::BxDi,
+            2 => Base::BpSi,
+            3 => Base::BpDi,
+            4 => Base::Si,
+            5 => Base::Di,
+            6 if md == 0 => Base::None,
+            6 => Base::Bp,
+            _ => Base::Bx,
+        };
+        let disp = match md {
+            0 if base == Base::None => { let lo = fetch() as u16; (fetch() as u16) << 8 | lo }
+            0 => 0,
+            1 => fetch() as i8 as u16,
+            _ => { let lo = fetch() as u16; (fetch() as u16) << 8 | lo }
+        };
+        ModRm { reg, rm: Operand::Mem(Address { seg: None, base, disp }) }
+    }
+}
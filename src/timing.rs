@@ -0,0 +1,163 @@
+// Approximate 8086 clock counts, after the tables in Intel's 8086 family
+// user's manual. The prefetch queue, wait states and the extra bus cycle of
+// word accesses at odd addresses are not modelled; where the manual gives a
+// range (MUL, DIV) the middle of it is used.
+
+use crate::decode::{Instruction, Op, Width};
+use crate::modrm::{Address, Base, Operand};
+
+// effective address calculation, including a segment override prefix
+fn ea(addr: Address) -> u32 {
+    let disp = addr.disp != 0;
+    let clocks = match addr.base {
+        Base::None => 6,
+        Base::Si | Base::Di | Base::Bp | Base::Bx => if disp { 9 } else { 5 },
+        Base::BpDi | Base::BxSi => if disp { 11 } else { 7 },
+        Base::BpSi | Base::BxDi => if disp { 12 } else { 8 },
+    };
+    clocks + if addr.seg.is_some() { 2 } else { 0 }
+}
+
+// `cl` and `cx` are the register values before the instruction ran, `taken`
+// whether it transferred control away from the next instruction
+pub fn clocks(insn: &Instruction, cl: u8, cx: u16, taken: bool) -> u32 {
+    use Operand::{Imm, Mem, Reg, Seg};
+    let Instruction { op, width: w, dst, src, .. } = *insn;
+    let byte = w == Width::Byte;
+    let branch = |yes, no| if taken { yes } else { no };
+    match op {
+        Op::Add | Op::Or | Op::Adc | Op::Sbb | Op::And | Op::Sub | Op::Xor | Op::Cmp => match (dst, src) {
+            (Reg(_), Reg(_)) => 3,
+            (Reg(_), Mem(a)) => 9 + ea(a),
+            (Mem(a), Reg(_)) => if op == Op::Cmp { 9 + ea(a) } else { 16 + ea(a) },
+            (Mem(a), _) => if op == Op::Cmp { 10 + ea(a) } else { 17 + ea(a) },
+            _ => 4,
+        },
+        Op::Test => match (dst, src) {
+            (Reg(_), Reg(_)) => 3,
+            (Mem(a), Imm(_)) => 11 + ea(a),
+            (Mem(a), _) | (_, Mem(a)) => 9 + ea(a),
+            _ => 5,
+        },
+        Op::Mov => match (dst, src) {
+            (Reg(_), Mem(a)) | (Mem(a), Reg(_)) if (0xA0..=0xA3).contains(&insn.opcode) => 4 + ea(a),
+            (Reg(_) | Seg(_), Mem(a)) => 8 + ea(a),
+            (Mem(a), Imm(_)) => 10 + ea(a),
+            (Mem(a), _) => 9 + ea(a),
+            (_, Imm(_)) => 4,
+            _ => 2,
+        },
+        Op::Inc | Op::Dec => match dst {
+            Mem(a) => 15 + ea(a),
+            _ => if byte { 3 } else { 2 },
+        },
+        Op::Not | Op::Neg => match dst {
+            Mem(a) => 16 + ea(a),
+            _ => 3,
+        },
+        Op::Mul | Op::Imul | Op::Div | Op::Idiv => {
+            let reg = match (op, byte) {
+                (Op::Mul, true) => 74,
+                (Op::Mul, false) => 126,
+                (Op::Imul, true) => 89,
+                (Op::Imul, false) => 141,
+                (Op::Div, true) => 85,
+                (Op::Div, false) => 153,
+                (_, true) => 107,
+                (_, false) => 175,
+            };
+            match dst {
+                Mem(a) => reg + 6 + ea(a),
+                _ => reg,
+            }
+        }
+        Op::Rol | Op::Ror | Op::Rcl | Op::Rcr | Op::Shl | Op::Shr | Op::Sar => match (dst, src) {
+            (Mem(a), Operand::Cl) => 20 + ea(a) + 4 * cl as u32,
+            (Mem(a), _) => 15 + ea(a),
+            (_, Operand::Cl) => 8 + 4 * cl as u32,
+            _ => 2,
+        },
+
+        Op::Push => match dst {
+            Mem(a) => 16 + ea(a),
+            Seg(_) => 10,
+            _ => 11,
+        },
+        Op::Pop => match dst {
+            Mem(a) => 17 + ea(a),
+            _ => 8,
+        },
+        Op::Pushf => 10,
+        Op::Popf => 8,
+        Op::Xchg => match (dst, src) {
+            (Mem(a), _) => 17 + ea(a),
+            (Reg(0), Reg(_)) if !byte => 3,
+            _ => 4,
+        },
+        Op::Lea => match src {
+            Mem(a) => 2 + ea(a),
+            _ => 2,
+        },
+        Op::Lds | Op::Les => match src {
+            Mem(a) => 16 + ea(a),
+            _ => 16,
+        },
+
+        Op::Cbw => 2,
+        Op::Cwd => 5,
+        Op::Lahf | Op::Sahf | Op::Daa | Op::Das => 4,
+        Op::Aaa | Op::Aas => 8,
+        Op::Aam => 83,
+        Op::Aad => 60,
+        Op::Xlat => 11,
+
+        Op::Jcc(_) => branch(16, 4),
+        Op::Jmp => match dst {
+            Mem(a) => 18 + ea(a),
+            Reg(_) => 11,
+            _ => 15,
+        },
+        Op::JmpFar => match dst {
+            Mem(a) => 24 + ea(a),
+            _ => 15,
+        },
+        Op::Call => match dst {
+            Mem(a) => 21 + ea(a),
+            Reg(_) => 16,
+            _ => 19,
+        },
+        Op::CallFar => match dst {
+            Mem(a) => 37 + ea(a),
+            _ => 28,
+        },
+        Op::Ret => if dst == Operand::None { 8 } else { 12 },
+        Op::RetFar => if dst == Operand::None { 18 } else { 17 },
+        Op::Loop => branch(17, 5),
+        Op::Loopz => branch(18, 6),
+        Op::Loopnz => branch(19, 5),
+        Op::Jcxz => branch(18, 6),
+
+        Op::Int => 51,
+        Op::Int3 => 52,
+        Op::Into => branch(53, 4),
+        Op::Iret => 24,
+        Op::In | Op::Out => if dst == Operand::Dx || src == Operand::Dx { 8 } else { 10 },
+
+        // one iteration; a repeated instruction with CX = 0 only pays for the prefix
+        Op::Movs | Op::Cmps | Op::Stos | Op::Lods | Op::Scas if insn.rep.is_some() && cx == 0 => 9,
+        Op::Movs => 17,
+        Op::Cmps => 22,
+        Op::Stos => 10,
+        Op::Lods => 12,
+        Op::Scas => 15,
+
+        Op::Clc | Op::Stc | Op::Cmc | Op::Cld | Op::Std | Op::Cli | Op::Sti => 2,
+        Op::Nop | Op::Wait => 3,
+        Op::Hlt => 2,
+        Op::Esc => match dst {
+            Mem(a) => 8 + ea(a),
+            _ => 2,
+        },
+        Op::Invalid => 0,
+    }
+}